@@ -5,8 +5,11 @@
 CARGO_ARGS=()
 
 function set_cargo_args() {
-    if [ "$FEATURE_BUILD" != "default" ]; then
-        CARGO_ARGS+=(--no-default-features)
+    if [ "$FEATURE_BUILD" == "no-default" ]; then
+        # Every build needs an HMAC backend, so use the one without C dependencies
+        CARGO_ARGS+=(--no-default-features --features rustcrypto)
+    elif [ "$FEATURE_BUILD" != "default" ]; then
+        CARGO_ARGS+=(--no-default-features --features "$FEATURE_BUILD")
     fi
 }
 
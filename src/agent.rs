@@ -0,0 +1,517 @@
+//! A long running agent that keeps unlocked secrets in memory and generates codes for the CLI.
+//!
+//! Like `ssh-agent`, the agent listens on a Unix socket only its user can access, which clients
+//! find through the `OTPCLI_AGENT_SOCK` environment variable. Each connection carries a single
+//! request line, `TOKEN <name>` for the current code, `TOKEN_AT <seconds> <name>` for the code
+//! at a time since the UNIX epoch or `PIN <name>` for the stored PIN or password, answered with
+//! `OK <code or PIN>` (a bare `OK` when no PIN is stored) or `ERR <message>`. The agent exits
+//! once no request has arrived for its idle timeout.
+//!
+//! Whenever the config has been saved since the agent last read it, entries that changed are
+//! unlocked again before a request is answered, and entries that can no longer be unlocked are
+//! refused, so clients fall back to reading the config themselves.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::{self, SecretLocation, TotpOptions};
+use crate::secret::SecretString;
+use crate::{secrets, token_at, totp, TotpConfigError, TotpError, TotpResult};
+
+/// The environment variable clients find the agent's socket through
+pub const AGENT_SOCK_ENV: &str = "OTPCLI_AGENT_SOCK";
+
+/// How long a client may take to send its request, or the agent to answer it
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the agent checks for new connections and its idle timeout
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The socket the agent listens on by default: `$XDG_RUNTIME_DIR/otpcli/agent.sock`, or
+/// `/tmp/otpcli-<uid>/agent.sock` without a runtime directory
+pub fn default_socket_path() -> PathBuf {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("otpcli"),
+        // SAFETY: getuid can't fail
+        None => std::env::temp_dir().join(format!("otpcli-{}", unsafe { libc::getuid() })),
+    };
+    dir.join("agent.sock")
+}
+
+/// Unlocked copies of config entries, with their secrets and PINs held in memory
+pub struct Agent {
+    entries: HashMap<String, Entry>,
+    config_dir: PathBuf,
+    /// When the config the entries were unlocked from was saved
+    config_modified: Option<SystemTime>,
+    idle_timeout: Duration,
+}
+
+/// An unlocked entry, with the fingerprint of the config entry it was unlocked from
+struct Entry {
+    options: TotpOptions,
+    fingerprint: u64,
+}
+
+impl Agent {
+    /// Unlocks the secrets of every entry in the config in `config_dir`, asking for the master
+    /// passphrase or keychain access as needed.
+    ///
+    /// Entries that can't be unlocked are left out of the agent and returned with the reason.
+    pub fn unlock<P: AsRef<Path>>(
+        config_dir: P,
+        idle_timeout: Duration,
+    ) -> TotpResult<(Agent, Vec<(String, String)>)> {
+        let mut agent = Agent {
+            entries: HashMap::new(),
+            config_dir: config_dir.as_ref().to_path_buf(),
+            config_modified: None,
+            idle_timeout,
+        };
+        let mut failures = agent.reload()?;
+        failures.sort();
+        Ok((agent, failures))
+    }
+
+    /// Reads the config again if it was saved since it was last read, unlocking the entries
+    /// that are new or changed and dropping the ones that were removed.
+    ///
+    /// Keychain entries are always unlocked again, as their secret or PIN can be replaced
+    /// without their config entry changing.
+    fn reload(&mut self) -> TotpResult<Vec<(String, String)>> {
+        // Read before the config, so a save in between is noticed by the next request
+        let modified = config::config_modified(&self.config_dir);
+        if modified.is_some() && modified == self.config_modified {
+            return Ok(Vec::new());
+        }
+        let config = config::load_config(&self.config_dir)?;
+        self.config_modified = modified;
+
+        let mut entries = HashMap::new();
+        let mut failures = Vec::new();
+        for (name, options) in config.codes() {
+            let fingerprint = fingerprint(options);
+            match self.entries.remove(name) {
+                Some(entry) if entry.fingerprint == fingerprint && !is_keychain(options) => {
+                    entries.insert(name.clone(), entry);
+                }
+                _ => match unlocked(name, options) {
+                    Ok(options) => {
+                        let entry = Entry {
+                            options,
+                            fingerprint,
+                        };
+                        entries.insert(name.clone(), entry);
+                    }
+                    Err(e) => failures.push((name.clone(), e.to_string())),
+                },
+            }
+        }
+        self.entries = entries;
+        Ok(failures)
+    }
+
+    /// The names of the entries the agent can generate codes for, sorted
+    pub fn names(&self) -> Vec<&String> {
+        let mut names: Vec<&String> = self.entries.keys().collect();
+        names.sort();
+        names
+    }
+
+    /// Answers requests on `listener`, bound to `socket` by [`listen`], until the idle timeout
+    /// passes
+    pub fn serve<P: AsRef<Path>>(&mut self, listener: UnixListener, socket: P) -> TotpResult<()> {
+        let result = self.accept_until_idle(&listener);
+        std::fs::remove_file(socket)?;
+        result
+    }
+
+    fn accept_until_idle(&mut self, listener: &UnixListener) -> TotpResult<()> {
+        listener.set_nonblocking(true)?;
+        let mut last_request = Instant::now();
+        while last_request.elapsed() < self.idle_timeout {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    // A misbehaving client shouldn't take the agent down with it
+                    let _ = self.handle(stream);
+                    last_request = Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(POLL_INTERVAL),
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, stream: UnixStream) -> TotpResult<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+        let mut request = String::new();
+        BufReader::new(&stream).read_line(&mut request)?;
+        // Without a readable config, nothing the agent holds can be trusted to be current
+        if self.reload().is_err() {
+            self.entries.clear();
+        }
+        let response = self.respond(request.trim_end_matches(&['\r', '\n'][..]));
+        (&stream).write_all(format!("{}\n", response).as_bytes())?;
+        Ok(())
+    }
+
+    fn respond(&self, request: &str) -> String {
+        let result = match request.split_once(' ') {
+            Some(("TOKEN", name)) => self.token(name, totp::time_since_epoch()),
+            Some(("PIN", name)) => self.pin(name),
+            Some(("TOKEN_AT", time_and_name)) => match time_and_name.split_once(' ') {
+                Some((seconds, name)) => match seconds.parse() {
+                    Ok(seconds) => self.token(name, Duration::from_secs(seconds)),
+                    Err(_) => Err(TotpConfigError(format!("Invalid time '{}'", seconds)).into()),
+                },
+                None => Err(TotpConfigError("Missing the name of the config".to_string()).into()),
+            },
+            _ => Err(TotpConfigError(format!("Unknown agent request '{}'", request)).into()),
+        };
+        match result {
+            Ok(value) if value.is_empty() => "OK".to_string(),
+            Ok(value) => format!("OK {}", value),
+            Err(e) => format!("ERR {}", e.to_string().replace('\n', " ")),
+        }
+    }
+
+    fn token(&self, name: &str, time_since_epoch: Duration) -> TotpResult<String> {
+        token_at(name, self.entry(name)?, time_since_epoch)
+    }
+
+    /// The stored PIN of `name`, or an empty string when it has none
+    fn pin(&self, name: &str) -> TotpResult<String> {
+        let pin = self.entry(name)?.pin();
+        Ok(pin.map(|pin| pin.expose().to_string()).unwrap_or_default())
+    }
+
+    fn entry(&self, name: &str) -> TotpResult<&TotpOptions> {
+        let entry = self
+            .entries
+            .get(name)
+            .ok_or_else(|| TotpConfigError(format!("The agent has no config named '{}'", name)))?;
+        Ok(&entry.options)
+    }
+}
+
+fn is_keychain(options: &TotpOptions) -> bool {
+    options.storage().unwrap_or(&SecretLocation::Config) != &SecretLocation::Config
+}
+
+/// A hash of everything a config entry stores, to notice when it changes
+fn fingerprint(options: &TotpOptions) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    // The entry can hold an unencrypted secret, so the serialized copy is zeroed once hashed
+    match toml::to_string(options) {
+        Ok(serialized) => SecretString::new(serialized).expose().hash(&mut hasher),
+        Err(_) => return 0,
+    }
+    hasher.finish()
+}
+
+/// A config-stored copy of an entry with its secret and PIN unlocked
+fn unlocked(name: &str, options: &TotpOptions) -> TotpResult<TotpOptions> {
+    let secret = secrets::get_secret(name, options)?;
+    let pin = secrets::get_pin(name, options)?;
+    lock_in_memory(&secret);
+    if let Some(pin) = &pin {
+        lock_in_memory(pin);
+    }
+
+    let mut unlocked = TotpOptions::new_config_stored_secret(secret, options.algorithm());
+    unlocked.copy_metadata_from(options);
+    unlocked.set_ocra_suite(options.ocra_suite().cloned());
+    unlocked.set_pin(pin);
+    Ok(unlocked)
+}
+
+/// Keeps a secret's memory from being swapped to disk, on a best effort basis: `mlock` fails
+/// once the user's locked memory limit is reached
+fn lock_in_memory(secret: &SecretString) {
+    let bytes = secret.expose().as_bytes();
+    // SAFETY: the range is a live allocation, which mlock only changes the paging of. Its
+    // pages stay locked after it's freed, which is harmless.
+    unsafe {
+        libc::mlock(bytes.as_ptr() as *const libc::c_void, bytes.len());
+    }
+}
+
+/// The background agent's connection to the process that started it, which waits until the
+/// agent is ready
+pub struct Daemon {
+    started: UnixStream,
+}
+
+impl Daemon {
+    /// Detaches the agent from the terminal, once it is done prompting, and lets the process
+    /// that started it exit
+    pub fn detach(self) -> TotpResult<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let null = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")?;
+        // SAFETY: only changes the session and which files the standard streams refer to
+        unsafe {
+            libc::setsid();
+            for fd in 0..3 {
+                libc::dup2(null.as_raw_fd(), fd);
+            }
+        }
+        (&self.started).write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+/// Forks the agent into the background, before anything is unlocked.
+///
+/// The background process gets the [`Daemon`] to detach with once it has unlocked the secrets,
+/// using the terminal if it needs to. The starting process waits until then and gets `None`.
+pub fn daemonize() -> TotpResult<Option<Daemon>> {
+    let (parent, child) = UnixStream::pair()?;
+    // SAFETY: nothing has unlocked secrets yet, which is where keychain access could start
+    // threads, so the process only has this thread and the child can keep running Rust code
+    match unsafe { libc::fork() } {
+        -1 => Err(Box::new(std::io::Error::last_os_error())),
+        0 => Ok(Some(Daemon { started: child })),
+        _ => {
+            drop(child);
+            let mut ready = [0; 1];
+            match (&parent).read(&mut ready)? {
+                0 => Err(Box::new(TotpError("The agent exited before it was ready"))),
+                _ => Ok(None),
+            }
+        }
+    }
+}
+
+/// Binds the agent's socket, in a directory and with permissions only the user can access
+pub fn listen<P: AsRef<Path>>(socket: P) -> TotpResult<UnixListener> {
+    let socket = socket.as_ref();
+    if let Some(dir) = socket.parent() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        check_private_dir(dir)?;
+    }
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(Box::new(TotpConfigError(format!(
+                "An agent is already listening on {}",
+                socket.display()
+            ))));
+        }
+        // Left behind by an agent that didn't exit cleanly
+        std::fs::remove_file(socket)?;
+    }
+
+    let listener = UnixListener::bind(socket)?;
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Refuses a socket directory anyone else could have created or could write to, like one
+/// planted at the predictable `/tmp/otpcli-<uid>` before the agent first ran
+fn check_private_dir(dir: &Path) -> TotpResult<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = std::fs::symlink_metadata(dir)?;
+    // SAFETY: getuid can't fail
+    let uid = unsafe { libc::getuid() };
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(Box::new(TotpConfigError(format!(
+            "Refusing to use {} for the agent's socket, it must be a directory only you own \
+             and can access (mode 0700)",
+            dir.display()
+        ))));
+    }
+    Ok(())
+}
+
+/// Asks the agent listening on `socket` for the current code of `name`
+pub fn request_token<P: AsRef<Path>>(socket: P, name: &str) -> TotpResult<String> {
+    request(socket, &format!("TOKEN {}", name))
+}
+
+/// Asks the agent listening on `socket` for the code of `name` at a time since the UNIX epoch
+pub fn request_token_at<P: AsRef<Path>>(
+    socket: P,
+    name: &str,
+    time_since_epoch: Duration,
+) -> TotpResult<String> {
+    request(
+        socket,
+        &format!("TOKEN_AT {} {}", time_since_epoch.as_secs(), name),
+    )
+}
+
+/// Asks the agent listening on `socket` for the PIN or password stored for `name`
+pub fn request_pin<P: AsRef<Path>>(socket: P, name: &str) -> TotpResult<Option<SecretString>> {
+    let pin = request(socket, &format!("PIN {}", name))?;
+    Ok(Some(pin)
+        .filter(|pin| !pin.is_empty())
+        .map(SecretString::new))
+}
+
+fn request<P: AsRef<Path>>(socket: P, request: &str) -> TotpResult<String> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.write_all(format!("{}\n", request).as_bytes())?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    match response.trim_end().split_once(' ') {
+        None if response.trim_end() == "OK" => Ok(String::new()),
+        Some(("OK", value)) => Ok(value.to_string()),
+        Some(("ERR", message)) => Err(Box::new(TotpConfigError(message.to_string()))),
+        _ => Err(Box::new(TotpConfigError(format!(
+            "Unexpected agent response '{}'",
+            response.trim_end()
+        )))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::totp::TokenAlgorithm;
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.insert(
+            "example".to_string(),
+            TotpOptions::new_config_stored_secret(
+                "GEZDGNBVGY3TQOJQ".into(),
+                TokenAlgorithm::TotpSha1,
+            ),
+        );
+        let mut vpn = TotpOptions::new_config_stored_secret(
+            "GEZDGNBVGY3TQOJQ".into(),
+            TokenAlgorithm::TotpSha1,
+        );
+        vpn.set_pin(Some("hunter2".into()));
+        config.insert("vpn".to_string(), vpn);
+        config
+    }
+
+    fn agent(name: &str) -> TotpResult<Agent> {
+        let dir =
+            std::env::temp_dir().join(format!("otpcli-agent-{}-test-{}", name, std::process::id()));
+        config::save_config(&dir, &config())?;
+        let (agent, failures) = Agent::unlock(&dir, Duration::from_millis(500))?;
+        assert!(failures.is_empty());
+        Ok(agent)
+    }
+
+    #[test]
+    fn requests_are_answered() -> TotpResult<()> {
+        let agent = agent("requests")?;
+        assert_eq!(agent.names(), ["example", "vpn"]);
+
+        let response = agent.respond("TOKEN example");
+        let code = response.strip_prefix("OK ").expect("a code");
+        assert_eq!(code.len(), 6);
+
+        assert!(agent.respond("TOKEN missing").starts_with("ERR "));
+        let expected = token_at(
+            "example",
+            &agent.entries["example"].options,
+            Duration::from_secs(59),
+        )?;
+        assert_eq!(
+            agent.respond("TOKEN_AT 59 example"),
+            format!("OK {}", expected)
+        );
+        assert!(agent.respond("TOKEN_AT soon example").starts_with("ERR "));
+        assert!(agent.respond("SHUTDOWN").starts_with("ERR "));
+
+        assert_eq!(agent.respond("PIN vpn"), "OK hunter2");
+        assert_eq!(agent.respond("PIN example"), "OK");
+        assert!(agent.respond("PIN missing").starts_with("ERR "));
+        std::fs::remove_dir_all(agent.config_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn entries_follow_config_changes() -> TotpResult<()> {
+        let mut agent = agent("reload")?;
+        let mut config = config();
+        config
+            .lookup_mut("vpn")?
+            .set_pin(Some("correct horse".into()));
+        config.remove("example");
+        config.insert(
+            "new".to_string(),
+            TotpOptions::new_config_stored_secret(
+                "JBSWY3DPEHPK3PXP".into(),
+                TokenAlgorithm::TotpSha1,
+            ),
+        );
+        // Make sure the save is seen even on file systems with coarse timestamps
+        std::thread::sleep(Duration::from_millis(10));
+        config::save_config(&agent.config_dir, &config)?;
+        agent.config_modified = Some(SystemTime::UNIX_EPOCH);
+
+        assert!(agent.reload()?.is_empty());
+        assert_eq!(agent.names(), ["new", "vpn"]);
+        assert_eq!(agent.respond("PIN vpn"), "OK correct horse");
+        assert!(agent.respond("TOKEN example").starts_with("ERR "));
+
+        std::fs::remove_file(agent.config_dir.join("config.toml"))?;
+        assert!(agent.reload()?.is_empty());
+        assert!(agent.names().is_empty());
+        std::fs::remove_dir_all(agent.config_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn clients_get_codes_over_the_socket() -> TotpResult<()> {
+        let dir = std::env::temp_dir().join(format!("otpcli-agent-test-{}", std::process::id()));
+        let socket = dir.join("agent.sock");
+        let listener = listen(&socket)?;
+        let mode = std::fs::metadata(&socket)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut agent = agent("socket")?;
+        let config_dir = agent.config_dir.clone();
+        let server = std::thread::spawn(move || agent.accept_until_idle(&listener).is_ok());
+
+        let code = request_token(&socket, "example")?;
+        assert_eq!(code.len(), 6);
+        assert!(request_token(&socket, "missing").is_err());
+        let pin = request_pin(&socket, "vpn")?;
+        assert_eq!(pin.as_ref().map(SecretString::expose), Some("hunter2"));
+        assert!(request_pin(&socket, "example")?.is_none());
+
+        assert!(server.join().expect("agent thread"));
+        std::fs::remove_dir_all(dir)?;
+        std::fs::remove_dir_all(config_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn sockets_are_only_made_in_private_directories() -> TotpResult<()> {
+        let dir = std::env::temp_dir().join(format!("otpcli-agent-dir-{}", std::process::id()));
+        std::fs::create_dir(&dir)?;
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755))?;
+        assert!(listen(dir.join("agent.sock")).is_err());
+        assert!(!dir.join("agent.sock").exists());
+
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+        listen(dir.join("agent.sock"))?;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -1,22 +1,101 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::default::Default;
-use std::io::Result as IoResult;
+use std::fmt::{Debug, Display, Error as FmtError, Formatter, Result as FmtResult};
+use std::io::{Result as IoResult, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
+use crate::secret::{SecretString, REDACTED};
 use crate::totp::TokenAlgorithm;
 use crate::{TotpConfigError, TotpResult};
 use serde::{self, Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, Default)]
 pub struct Config {
+    #[serde(default, skip_serializing_if = "ClipboardConfig::is_default")]
+    clipboard: ClipboardConfig,
+    #[serde(default, skip_serializing_if = "OutputConfig::is_default")]
+    output: OutputConfig,
     totp: HashMap<String, TotpOptions>,
 }
 
+/// How codes copied to the clipboard are handled, from the `[clipboard]` section of the config
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct ClipboardConfig {
+    /// Seconds after which a copied code is cleared from the clipboard, or 0 to leave it there
+    clear_after: Option<u64>,
+    /// Whether to put back what was on the clipboard before the code was copied
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    restore: bool,
+}
+
+/// How long copied codes stay on the clipboard unless configured otherwise
+pub const DEFAULT_CLIPBOARD_CLEAR_AFTER: Duration = Duration::from_secs(30);
+
+impl ClipboardConfig {
+    /// How long copied codes stay on the clipboard, or `None` to leave them there
+    pub fn clear_after(&self) -> Option<Duration> {
+        match self.clear_after {
+            None => Some(DEFAULT_CLIPBOARD_CLEAR_AFTER),
+            Some(0) => None,
+            Some(seconds) => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    pub fn restore(&self) -> bool {
+        self.restore
+    }
+
+    fn is_default(&self) -> bool {
+        *self == ClipboardConfig::default()
+    }
+}
+
+/// How generated codes are printed, from the `[output]` section of the config
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct OutputConfig {
+    /// The template codes are printed with, like `{name}: {code} ({remaining}s)`
+    format: Option<String>,
+    /// The size of the groups the digits of printed codes are split into, like `123 456`
+    group_digits: Option<usize>,
+}
+
+impl OutputConfig {
+    pub fn format(&self) -> Option<&str> {
+        self.format.as_deref()
+    }
+
+    pub fn group_digits(&self) -> Option<usize> {
+        self.group_digits
+    }
+
+    fn is_default(&self) -> bool {
+        *self == OutputConfig::default()
+    }
+}
+
+/// A copy of a [`Config`] with every config-stored secret and PIN masked, for showing to users.
+///
+/// It serializes (and displays) as the same TOML as the config file, but can't be saved in its
+/// place.
+#[derive(Serialize, Clone)]
+#[serde(transparent)]
+pub struct RedactedConfig(Config);
+
 impl Config {
     pub fn codes(&self) -> &HashMap<String, TotpOptions> {
         &self.totp
     }
 
+    pub fn clipboard(&self) -> &ClipboardConfig {
+        &self.clipboard
+    }
+
+    pub fn output(&self) -> &OutputConfig {
+        &self.output
+    }
+
     pub fn insert(&mut self, name: String, options: TotpOptions) {
         self.totp.insert(name, options);
     }
@@ -24,9 +103,48 @@ impl Config {
     pub fn remove(&mut self, name: &str) {
         self.totp.remove(name);
     }
+
+    /// A view of this config that is safe to print or dump
+    pub fn redacted(&self) -> RedactedConfig {
+        let mut config = self.clone();
+        for options in config.totp.values_mut() {
+            options.redact();
+        }
+        RedactedConfig(config)
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl Debug for Config {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        // Sorted, so the output is stable
+        let codes: BTreeMap<_, _> = self.totp.iter().collect();
+        f.debug_struct("Config")
+            .field("clipboard", &self.clipboard)
+            .field("output", &self.output)
+            .field("totp", &codes)
+            .finish()
+    }
+}
+
+impl RedactedConfig {
+    pub fn codes(&self) -> &HashMap<String, TotpOptions> {
+        self.0.codes()
+    }
+}
+
+impl Display for RedactedConfig {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", toml::to_string(self).map_err(|_| FmtError)?)
+    }
+}
+
+impl Debug for RedactedConfig {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_tuple("RedactedConfig").field(&self.0).finish()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum SecretLocation {
     #[serde(rename = "config")]
     Config,
@@ -35,11 +153,163 @@ pub enum SecretLocation {
     KeyChain,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+impl FromStr for SecretLocation {
+    type Err = TotpConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "config" => Ok(SecretLocation::Config),
+            #[cfg(feature = "keychain")]
+            "keychain" => Ok(SecretLocation::KeyChain),
+            _ => Err(TotpConfigError(format!(
+                "Unknown secret storage location '{}'",
+                s
+            ))),
+        }
+    }
+}
+
+impl Display for SecretLocation {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            SecretLocation::Config => write!(f, "config"),
+            #[cfg(feature = "keychain")]
+            SecretLocation::KeyChain => write!(f, "keychain"),
+        }
+    }
+}
+
+/// Where an entry's PIN or password goes when it is combined with a code, for logins that expect
+/// both in one field
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PinPosition {
+    /// `PIN+code`
+    #[default]
+    Prefix,
+    /// `code+PIN`
+    Suffix,
+}
+
+impl PinPosition {
+    /// Joins the PIN and the code in this order
+    pub fn combine(self, pin: &str, code: &str) -> String {
+        match self {
+            PinPosition::Prefix => format!("{}{}", pin, code),
+            PinPosition::Suffix => format!("{}{}", code, pin),
+        }
+    }
+}
+
+impl FromStr for PinPosition {
+    type Err = TotpConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "prefix" => Ok(PinPosition::Prefix),
+            "suffix" => Ok(PinPosition::Suffix),
+            _ => Err(TotpConfigError(format!(
+                "Unknown PIN position '{}', expected prefix or suffix",
+                s
+            ))),
+        }
+    }
+}
+
+impl Display for PinPosition {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            PinPosition::Prefix => write!(f, "prefix"),
+            PinPosition::Suffix => write!(f, "suffix"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct TotpOptions {
     storage: Option<SecretLocation>,
-    secret: Option<String>,
+    secret: Option<SecretString>,
+    /// The secret encrypted with the master passphrase, instead of `secret`
+    secret_enc: Option<String>,
     algorithm: Option<TokenAlgorithm>,
+    /// The PIN of tokens that need one, when it is stored in the config
+    pin: Option<SecretString>,
+    /// The PIN encrypted with the master passphrase, instead of `pin`
+    pin_enc: Option<String>,
+    /// Whether a PIN is stored in the keychain alongside the secret
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    keychain_pin: bool,
+    /// Whether `--with-pin` puts the PIN before or after the code, before by default
+    pin_position: Option<PinPosition>,
+    /// The OCRA suite of challenge-response tokens, e.g. `OCRA-1:HOTP-SHA1-6:QN08`
+    ocra_suite: Option<String>,
+    issuer: Option<String>,
+    account: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    notes: Option<String>,
+    /// Seconds since the UNIX epoch when this entry was first added
+    created_at: Option<u64>,
+    /// Seconds since the UNIX epoch when a code was last generated for this entry, kept in
+    /// `usage.toml` rather than the config
+    #[serde(skip)]
+    last_used_at: Option<u64>,
+}
+
+/// Changes to apply to the descriptive metadata of a [`TotpOptions`] entry.
+///
+/// Fields left as `None` are not changed, while an empty string clears the field.
+#[derive(Debug, Default, Clone)]
+pub struct MetadataUpdate {
+    pub issuer: Option<String>,
+    pub account: Option<String>,
+    pub notes: Option<String>,
+    pub add_tags: Vec<String>,
+    pub remove_tags: Vec<String>,
+    pub pin_position: Option<PinPosition>,
+}
+
+/// Selects which entries are returned when listing secrets
+#[derive(Debug, Default, Clone)]
+pub struct ListFilter {
+    pub prefix: Option<String>,
+    pub tag: Option<String>,
+    pub issuer: Option<String>,
+}
+
+impl ListFilter {
+    pub fn matches(&self, name: &str, options: &TotpOptions) -> bool {
+        let prefix_matches = self
+            .prefix
+            .as_ref()
+            .is_none_or(|prefix| name.starts_with(prefix.as_str()));
+        let tag_matches = self
+            .tag
+            .as_ref()
+            .is_none_or(|tag| options.tags().contains(tag));
+        let issuer_matches = self.issuer.as_ref().is_none_or(|issuer| {
+            options
+                .issuer()
+                .is_some_and(|i| i.eq_ignore_ascii_case(issuer))
+        });
+
+        prefix_matches && tag_matches && issuer_matches
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Can't get time since UNIX_EPOCH?")
+        .as_secs()
+}
+
+fn non_empty(value: String) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
 }
 
 impl TotpOptions {
@@ -47,37 +317,195 @@ impl TotpOptions {
         self.storage.as_ref()
     }
 
-    pub fn secret(&self) -> Option<&String> {
+    pub fn secret(&self) -> Option<&SecretString> {
         self.secret.as_ref()
     }
 
+    pub(crate) fn set_secret(&mut self, secret: Option<SecretString>) {
+        self.secret = secret;
+    }
+
+    pub fn encrypted_secret(&self) -> Option<&String> {
+        self.secret_enc.as_ref()
+    }
+
+    pub(crate) fn set_encrypted_secret(&mut self, secret: Option<String>) {
+        self.secret_enc = secret;
+    }
+
+    /// Whether the secret is stored in the config encrypted with the master passphrase
+    pub fn is_encrypted(&self) -> bool {
+        self.secret_enc.is_some()
+    }
+
     pub fn algorithm(&self) -> TokenAlgorithm {
         self.algorithm.unwrap_or(TokenAlgorithm::TotpSha1)
     }
 
-    pub fn new_config_stored_secret(secret: String, algorithm: TokenAlgorithm) -> Self {
+    pub fn pin(&self) -> Option<&SecretString> {
+        self.pin.as_ref()
+    }
+
+    pub fn encrypted_pin(&self) -> Option<&String> {
+        self.pin_enc.as_ref()
+    }
+
+    pub(crate) fn set_encrypted_pin(&mut self, pin: Option<String>) {
+        self.pin_enc = pin;
+    }
+
+    pub fn has_keychain_pin(&self) -> bool {
+        self.keychain_pin
+    }
+
+    pub(crate) fn set_pin(&mut self, pin: Option<SecretString>) {
+        self.pin = pin;
+    }
+
+    #[cfg(feature = "keychain")]
+    pub(crate) fn set_keychain_pin(&mut self, stored: bool) {
+        self.keychain_pin = stored;
+    }
+
+    pub fn pin_position(&self) -> PinPosition {
+        self.pin_position.unwrap_or_default()
+    }
+
+    pub fn ocra_suite(&self) -> Option<&String> {
+        self.ocra_suite.as_ref()
+    }
+
+    pub(crate) fn set_ocra_suite(&mut self, suite: Option<String>) {
+        self.ocra_suite = suite;
+    }
+
+    pub fn issuer(&self) -> Option<&String> {
+        self.issuer.as_ref()
+    }
+
+    pub fn account(&self) -> Option<&String> {
+        self.account.as_ref()
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn notes(&self) -> Option<&String> {
+        self.notes.as_ref()
+    }
+
+    pub fn created_at(&self) -> Option<u64> {
+        self.created_at
+    }
+
+    pub fn last_used_at(&self) -> Option<u64> {
+        self.last_used_at
+    }
+
+    fn new(
+        storage: SecretLocation,
+        secret: Option<SecretString>,
+        algorithm: TokenAlgorithm,
+    ) -> Self {
         TotpOptions {
-            storage: Some(SecretLocation::Config),
-            secret: Some(secret),
+            storage: Some(storage),
+            secret,
+            secret_enc: None,
             algorithm: Some(algorithm),
+            pin: None,
+            pin_enc: None,
+            keychain_pin: false,
+            pin_position: None,
+            ocra_suite: None,
+            issuer: None,
+            account: None,
+            tags: Vec::new(),
+            notes: None,
+            created_at: Some(unix_now()),
+            last_used_at: None,
         }
     }
 
+    pub fn new_config_stored_secret(secret: SecretString, algorithm: TokenAlgorithm) -> Self {
+        TotpOptions::new(SecretLocation::Config, Some(secret), algorithm)
+    }
+
     #[cfg(feature = "keychain")]
     pub fn new_keychain_stored_secret(algorithm: TokenAlgorithm) -> Self {
-        TotpOptions {
-            storage: Some(SecretLocation::KeyChain),
-            secret: None,
-            algorithm: Some(algorithm),
+        TotpOptions::new(SecretLocation::KeyChain, None, algorithm)
+    }
+
+    /// Applies the given metadata changes to this entry
+    pub fn update_metadata(&mut self, update: MetadataUpdate) {
+        if let Some(issuer) = update.issuer {
+            self.issuer = non_empty(issuer);
+        }
+        if let Some(account) = update.account {
+            self.account = non_empty(account);
+        }
+        if let Some(notes) = update.notes {
+            self.notes = non_empty(notes);
+        }
+        if let Some(position) = update.pin_position {
+            self.pin_position = Some(position);
+        }
+        let remove_tags = update.remove_tags;
+        self.tags.retain(|tag| !remove_tags.contains(tag));
+        for tag in update.add_tags {
+            if !self.tags.contains(&tag) {
+                self.tags.push(tag);
+            }
         }
     }
+
+    /// Copies the metadata (but not the secret, PIN or their storage) of another entry onto this one
+    pub fn copy_metadata_from(&mut self, other: &TotpOptions) {
+        self.pin_position = other.pin_position;
+        self.issuer = other.issuer.clone();
+        self.account = other.account.clone();
+        self.tags = other.tags.clone();
+        self.notes = other.notes.clone();
+        self.created_at = other.created_at;
+        self.last_used_at = other.last_used_at;
+    }
+
+    /// Makes this entry look newly added, for copies of another entry
+    pub(crate) fn mark_created(&mut self) {
+        self.created_at = Some(unix_now());
+        self.last_used_at = None;
+    }
+
+    fn redact(&mut self) {
+        let mask = |value: &mut Option<SecretString>| {
+            if value.is_some() {
+                *value = Some(SecretString::from(REDACTED));
+            }
+        };
+        mask(&mut self.secret);
+        mask(&mut self.pin);
+    }
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Config {
-            totp: HashMap::new(),
-        }
+impl Debug for TotpOptions {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("TotpOptions")
+            .field("storage", &self.storage)
+            .field("secret", &self.secret.as_ref().map(|_| REDACTED))
+            .field("secret_enc", &self.secret_enc)
+            .field("algorithm", &self.algorithm)
+            .field("pin", &self.pin.as_ref().map(|_| REDACTED))
+            .field("pin_enc", &self.pin_enc)
+            .field("keychain_pin", &self.keychain_pin)
+            .field("pin_position", &self.pin_position)
+            .field("ocra_suite", &self.ocra_suite)
+            .field("issuer", &self.issuer)
+            .field("account", &self.account)
+            .field("tags", &self.tags)
+            .field("notes", &self.notes)
+            .field("created_at", &self.created_at)
+            .field("last_used_at", &self.last_used_at)
+            .finish()
     }
 }
 
@@ -88,21 +516,124 @@ impl Config {
             .get(name)
             .ok_or_else(|| TotpConfigError(format!("Unable to find config named '{}'", name)))?)
     }
+
+    pub fn lookup_mut(&mut self, name: &str) -> TotpResult<&mut TotpOptions> {
+        Ok(self
+            .totp
+            .get_mut(name)
+            .ok_or_else(|| TotpConfigError(format!("Unable to find config named '{}'", name)))?)
+    }
+}
+
+/// When codes were last generated for each entry, kept in its own file so that generating a code
+/// never rewrites the config
+#[derive(Deserialize, Serialize, Default)]
+struct Usage {
+    #[serde(default)]
+    last_used_at: BTreeMap<String, u64>,
+}
+
+const USAGE_FILE: &str = "usage.toml";
+const CONFIG_FILE: &str = "config.toml";
+
+impl Usage {
+    fn load(config_dir: &Path) -> Usage {
+        // Only informational, so a missing or damaged file starts over
+        std::fs::read_to_string(config_dir.join(USAGE_FILE))
+            .ok()
+            .and_then(|usage| toml::from_str(&usage).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, config_dir: &Path) -> TotpResult<()> {
+        replace_file(config_dir, USAGE_FILE, &toml::to_string(self)?)?;
+        Ok(())
+    }
 }
 
 pub fn load_config<P: AsRef<Path>>(config_dir: P) -> IoResult<Config> {
-    let config_path: PathBuf = config_dir.as_ref().join("config.toml");
+    let config_path: PathBuf = config_dir.as_ref().join(CONFIG_FILE);
 
-    let config: Config = if config_path.exists() {
+    let mut config: Config = if config_path.exists() {
         let config = std::fs::read_to_string(config_path)?;
         toml::from_str(&config).expect("Unable to read config as TOML")
     } else {
         Config::default()
     };
 
+    let usage = Usage::load(config_dir.as_ref());
+    for (name, options) in config.totp.iter_mut() {
+        options.last_used_at = usage.last_used_at.get(name).copied();
+    }
     Ok(config)
 }
 
+/// When the config in `config_dir` was last saved, if it exists
+pub fn config_modified<P: AsRef<Path>>(config_dir: P) -> Option<SystemTime> {
+    std::fs::metadata(config_dir.as_ref().join(CONFIG_FILE))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+pub fn save_config<P: AsRef<Path>>(config_dir: P, config: &Config) -> TotpResult<()> {
+    let string = toml::to_string(config)?;
+    ensure_config_dir(&config_dir)?;
+    replace_file(config_dir.as_ref(), CONFIG_FILE, &string)?;
+
+    // Follows the entries that were renamed, copied or removed
+    let usage = Usage {
+        last_used_at: config
+            .totp
+            .iter()
+            .filter_map(|(name, options)| Some((name.clone(), options.last_used_at?)))
+            .collect(),
+    };
+    if !usage.last_used_at.is_empty() || config_dir.as_ref().join(USAGE_FILE).exists() {
+        usage.save(config_dir.as_ref())?;
+    }
+    Ok(())
+}
+
+/// Records that codes were just generated for `names`, in `usage.toml` next to the config
+pub fn save_usage<P: AsRef<Path>>(config_dir: P, names: &[&str]) -> TotpResult<()> {
+    let mut usage = Usage::load(config_dir.as_ref());
+    let now = unix_now();
+    for name in names {
+        usage.last_used_at.insert(name.to_string(), now);
+    }
+    ensure_config_dir(&config_dir)?;
+    usage.save(config_dir.as_ref())
+}
+
+/// Replaces `file_name` in `dir` with `contents`, keeping its permissions. The contents are
+/// written to a temporary file and renamed over it, so the secrets in it can't be lost to a write
+/// cut short or racing with another invocation
+fn replace_file(dir: &Path, file_name: &str, contents: &str) -> IoResult<()> {
+    let path = dir.join(file_name);
+    let temp_path = dir.join(format!(".{}.{}", file_name, std::process::id()));
+    write_private(&temp_path, contents)?;
+    let result = match std::fs::metadata(&path) {
+        Ok(metadata) => std::fs::set_permissions(&temp_path, metadata.permissions()),
+        Err(_) => Ok(()),
+    }
+    .and_then(|_| std::fs::rename(&temp_path, &path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Writes a new file only the current user can read until its permissions are changed
+fn write_private(path: &Path, contents: &str) -> IoResult<()> {
+    // A file left by an earlier invocation with the same pid would keep its permissions
+    let _ = std::fs::remove_file(path);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
 fn make_config_dir<P: AsRef<Path>>(config_dir: P) -> IoResult<()> {
     std::fs::create_dir_all(config_dir)
 }
@@ -119,3 +650,225 @@ pub fn ensure_config_dir<P: AsRef<Path>>(config_dir: P) -> IoResult<()> {
         Ok(_) => Ok(()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+    const PIN: &str = "5937";
+
+    fn config() -> Config {
+        let mut options =
+            TotpOptions::new_config_stored_secret(SECRET.into(), TokenAlgorithm::Motp);
+        options.set_pin(Some(PIN.into()));
+        options.update_metadata(MetadataUpdate {
+            issuer: Some("Example".to_string()),
+            ..MetadataUpdate::default()
+        });
+        let mut config = Config::default();
+        config.insert("example".to_string(), options);
+        config
+    }
+
+    #[test]
+    fn debug_output_masks_secrets() -> TotpResult<()> {
+        let config = config();
+        let outputs = [
+            format!("{:?}", config),
+            format!("{:#?}", config),
+            format!("{:?}", config.lookup("example")?),
+            format!("{:?}", config.redacted()),
+        ];
+        for output in outputs.iter() {
+            assert!(!output.contains(SECRET), "{}", output);
+            assert!(!output.contains(PIN), "{}", output);
+            assert!(output.contains(REDACTED));
+            assert!(output.contains("Example"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn redacted_config_masks_secrets_but_keeps_metadata() -> TotpResult<()> {
+        let config = config();
+        let redacted = config.redacted();
+
+        let dump = redacted.to_string();
+        assert!(!dump.contains(SECRET), "{}", dump);
+        assert!(!dump.contains(PIN), "{}", dump);
+        assert!(dump.contains(r#"secret = "<redacted>""#));
+        assert!(dump.contains(r#"issuer = "Example""#));
+
+        let options = &redacted.codes()["example"];
+        assert_eq!(options.algorithm(), TokenAlgorithm::Motp);
+        assert_eq!(options.secret().map(SecretString::expose), Some(REDACTED));
+
+        // The config itself keeps its secrets
+        let options = config.lookup("example")?;
+        assert_eq!(options.secret().map(SecretString::expose), Some(SECRET));
+        assert_eq!(options.pin().map(SecretString::expose), Some(PIN));
+        Ok(())
+    }
+
+    #[test]
+    fn clipboard_settings_default_to_clearing_after_30_seconds() -> TotpResult<()> {
+        let config: Config = toml::from_str("[totp]")?;
+        assert_eq!(
+            config.clipboard().clear_after(),
+            Some(DEFAULT_CLIPBOARD_CLEAR_AFTER)
+        );
+        assert!(!config.clipboard().restore());
+        assert!(!toml::to_string(&config)?.contains("clipboard"));
+
+        let config: Config = toml::from_str("[clipboard]\nclear_after = 0\n[totp]")?;
+        assert_eq!(config.clipboard().clear_after(), None);
+
+        let config: Config =
+            toml::from_str("[clipboard]\nclear_after = 10\nrestore = true\n[totp]")?;
+        assert_eq!(
+            config.clipboard().clear_after(),
+            Some(Duration::from_secs(10))
+        );
+        assert!(config.clipboard().restore());
+        assert!(toml::to_string(&config)?.contains("[clipboard]"));
+        Ok(())
+    }
+
+    #[test]
+    fn output_settings_are_optional() -> TotpResult<()> {
+        let config: Config = toml::from_str("[totp]")?;
+        assert_eq!(config.output().format(), None);
+        assert_eq!(config.output().group_digits(), None);
+        assert!(!toml::to_string(&config)?.contains("output"));
+
+        let config: Config =
+            toml::from_str("[output]\nformat = \"{name}: {code}\"\ngroup_digits = 3\n[totp]")?;
+        assert_eq!(config.output().format(), Some("{name}: {code}"));
+        assert_eq!(config.output().group_digits(), Some(3));
+        assert!(toml::to_string(&config)?.contains("[output]"));
+        Ok(())
+    }
+
+    #[test]
+    fn pins_go_before_codes_unless_configured_otherwise() -> TotpResult<()> {
+        let mut config = config();
+        let options = config.lookup_mut("example")?;
+        assert_eq!(options.pin_position(), PinPosition::Prefix);
+        assert_eq!(options.pin_position().combine(PIN, "123456"), "5937123456");
+
+        options.update_metadata(MetadataUpdate {
+            pin_position: Some("suffix".parse()?),
+            ..MetadataUpdate::default()
+        });
+        assert_eq!(options.pin_position().combine(PIN, "123456"), "1234565937");
+        assert!(toml::to_string(&config)?.contains("pin_position = \"suffix\""));
+        Ok(())
+    }
+
+    #[test]
+    fn usage_is_recorded_without_rewriting_the_config() -> TotpResult<()> {
+        let dir = std::env::temp_dir().join(format!("otpcli-usage-test-{}", std::process::id()));
+        save_config(&dir, &config())?;
+        let path = dir.join("config.toml");
+        let commented = format!("# Work accounts\n{}", std::fs::read_to_string(&path)?);
+        std::fs::write(&path, &commented)?;
+
+        save_usage(&dir, &["example"])?;
+        assert_eq!(std::fs::read_to_string(&path)?, commented);
+        let mut config = load_config(&dir)?;
+        let options = config.lookup("example")?.clone();
+        assert!(options.last_used_at().is_some());
+
+        config.remove("example");
+        config.insert("renamed".to_string(), options);
+        save_config(&dir, &config)?;
+        let usage = std::fs::read_to_string(dir.join(USAGE_FILE))?;
+        assert!(
+            usage.contains("renamed") && !usage.contains("example"),
+            "{}",
+            usage
+        );
+        assert!(load_config(&dir)?
+            .lookup("renamed")?
+            .last_used_at()
+            .is_some());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn saving_replaces_the_config_and_keeps_its_permissions() -> TotpResult<()> {
+        let dir = std::env::temp_dir().join(format!("otpcli-config-test-{}", std::process::id()));
+        save_config(&dir, &config())?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let path = dir.join("config.toml");
+            assert_eq!(
+                std::fs::metadata(&path)?.permissions().mode() & 0o777,
+                0o600
+            );
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640))?;
+            save_config(&dir, &config())?;
+            assert_eq!(
+                std::fs::metadata(&path)?.permissions().mode() & 0o777,
+                0o640
+            );
+        }
+
+        assert!(load_config(&dir)?.lookup("example").is_ok());
+        let files: Vec<_> = std::fs::read_dir(&dir)?.collect::<IoResult<_>>()?;
+        assert_eq!(files.len(), 1, "temporary files were left behind");
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn list_filters_match_prefixes_tags_and_issuers() {
+        let mut options =
+            TotpOptions::new_config_stored_secret(SECRET.into(), TokenAlgorithm::TotpSha1);
+        options.update_metadata(MetadataUpdate {
+            issuer: Some("GitHub".to_string()),
+            add_tags: vec!["work".to_string()],
+            ..MetadataUpdate::default()
+        });
+        let filter = |prefix: Option<&str>, tag: Option<&str>, issuer: Option<&str>| ListFilter {
+            prefix: prefix.map(str::to_string),
+            tag: tag.map(str::to_string),
+            issuer: issuer.map(str::to_string),
+        };
+
+        assert!(filter(None, None, None).matches("github", &options));
+        assert!(filter(Some("git"), Some("work"), Some("github")).matches("github", &options));
+        assert!(!filter(Some("hub"), None, None).matches("github", &options));
+        assert!(!filter(None, Some("home"), None).matches("github", &options));
+        assert!(!filter(None, None, Some("GitLab")).matches("github", &options));
+    }
+
+    #[test]
+    fn metadata_updates_add_and_remove_tags_and_clear_empty_fields() {
+        let mut options =
+            TotpOptions::new_config_stored_secret(SECRET.into(), TokenAlgorithm::TotpSha1);
+        options.update_metadata(MetadataUpdate {
+            issuer: Some("GitHub".to_string()),
+            account: Some("alice".to_string()),
+            notes: Some("recovery codes in the safe".to_string()),
+            add_tags: vec!["work".to_string(), "dev".to_string(), "work".to_string()],
+            ..MetadataUpdate::default()
+        });
+        assert_eq!(options.tags(), ["work", "dev"]);
+
+        options.update_metadata(MetadataUpdate {
+            account: Some(String::new()),
+            notes: Some(String::new()),
+            add_tags: vec!["home".to_string()],
+            remove_tags: vec!["work".to_string(), "unknown".to_string()],
+            ..MetadataUpdate::default()
+        });
+        assert_eq!(options.issuer().map(String::as_str), Some("GitHub"));
+        assert_eq!(options.account(), None);
+        assert_eq!(options.notes(), None);
+        assert_eq!(options.tags(), ["dev", "home"]);
+    }
+}
@@ -0,0 +1,273 @@
+//! Normalizes and decodes OTP secrets.
+//!
+//! Secrets are accepted as base32 (the encoding used by `otpauth://` URIs and most QR codes),
+//! hex, or raw ASCII. Base32 and hex input may contain lowercase letters, spaces, hyphens and
+//! (for base32) `=` padding, all of which are stripped before decoding.
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::str::FromStr;
+
+use crate::secret::{SecretBytes, SecretString};
+use crate::{TotpConfigError, TotpError, TotpResult};
+
+static ALPHABET: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+/// The encoding a secret was supplied in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecretEncoding {
+    Base32,
+    Hex,
+    Ascii,
+}
+
+impl FromStr for SecretEncoding {
+    type Err = TotpConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "base32" => Ok(SecretEncoding::Base32),
+            "hex" => Ok(SecretEncoding::Hex),
+            "ascii" | "raw" => Ok(SecretEncoding::Ascii),
+            _ => Err(TotpConfigError(format!("Unknown secret encoding '{}'", s))),
+        }
+    }
+}
+
+impl Display for SecretEncoding {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            SecretEncoding::Base32 => write!(f, "base32"),
+            SecretEncoding::Hex => write!(f, "hex"),
+            SecretEncoding::Ascii => write!(f, "ascii"),
+        }
+    }
+}
+
+/// Removes spaces, hyphens and `=` padding from a base32 secret and upper-cases it.
+pub fn clean_base32(secret: &str) -> String {
+    secret
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-' && *c != '=')
+        .collect::<String>()
+        .to_uppercase()
+}
+
+fn clean_hex(secret: &str) -> String {
+    let secret: String = secret
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-' && *c != ':')
+        .collect::<String>()
+        .to_lowercase();
+    match secret.strip_prefix("0x") {
+        Some(stripped) => stripped.to_string(),
+        None => secret,
+    }
+}
+
+fn decode_hex(secret: &str) -> TotpResult<Vec<u8>> {
+    if !secret.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Box::new(TotpError(
+            "Hex secret contains a non-hex character",
+        )));
+    }
+    if !secret.len().is_multiple_of(2) {
+        return Err(Box::new(TotpError(
+            "Hex secret has an odd number of digits",
+        )));
+    }
+
+    Ok((0..secret.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&secret[i..i + 2], 16).expect("validated hex digits"))
+        .collect())
+}
+
+/// Decodes a secret supplied in the given encoding into the raw key bytes.
+///
+/// # Examples
+/// ```rust
+/// use otp::encoding::{decode_secret, SecretEncoding};
+///
+/// let key = decode_secret("gezd-gnbv gy3t qojq", SecretEncoding::Base32).unwrap();
+/// assert_eq!(key.expose(), b"1234567890");
+///
+/// let key = decode_secret("3132 3334 3536 3738 3930", SecretEncoding::Hex).unwrap();
+/// assert_eq!(key.expose(), b"1234567890");
+/// ```
+pub fn decode_secret(secret: &str, encoding: SecretEncoding) -> TotpResult<SecretBytes> {
+    let key = SecretBytes::new(match encoding {
+        SecretEncoding::Base32 => {
+            let cleaned = SecretString::new(clean_base32(secret));
+            base32::decode(ALPHABET, cleaned.expose())
+                .ok_or(TotpError("Failed to decode secret from base32"))?
+        }
+        SecretEncoding::Hex => decode_hex(SecretString::new(clean_hex(secret)).expose())?,
+        SecretEncoding::Ascii => secret.as_bytes().to_vec(),
+    });
+
+    if key.is_empty() {
+        return Err(Box::new(TotpError("Secret is empty")));
+    }
+    Ok(key)
+}
+
+/// Validates a secret and converts it to the canonical base32 form otpcli stores.
+///
+/// Base32 secrets keep their characters (cleaned of spaces, hyphens and padding), while hex
+/// and ASCII secrets are re-encoded as base32.
+pub fn normalize_secret(secret: &str, encoding: SecretEncoding) -> TotpResult<SecretString> {
+    let key = decode_secret(secret, encoding)?;
+    Ok(SecretString::new(match encoding {
+        SecretEncoding::Base32 => clean_base32(secret),
+        SecretEncoding::Hex | SecretEncoding::Ascii => base32::encode(ALPHABET, key.expose()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"12345678901234567890";
+    const BASE32_KEY: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn base32_accepts_canonical_secret() -> TotpResult<()> {
+        assert_eq!(
+            decode_secret(BASE32_KEY, SecretEncoding::Base32)?.expose(),
+            KEY
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn base32_accepts_lowercase_spaces_hyphens_and_padding() -> TotpResult<()> {
+        let inputs = [
+            "gezdgnbvgy3tqojqgezdgnbvgy3tqojq",
+            "GEZD GNBV GY3T QOJQ GEZD GNBV GY3T QOJQ",
+            "gezd-gnbv-gy3t-qojq-gezd-gnbv-gy3t-qojq",
+            " GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\t",
+        ];
+        for input in inputs.iter() {
+            assert_eq!(decode_secret(input, SecretEncoding::Base32)?.expose(), KEY);
+            assert_eq!(
+                normalize_secret(input, SecretEncoding::Base32)?.expose(),
+                BASE32_KEY
+            );
+        }
+
+        assert_eq!(
+            decode_secret("GEZDGNBVGY======", SecretEncoding::Base32)?.expose(),
+            b"123456"
+        );
+        assert_eq!(
+            normalize_secret("gezdgnbvgy======", SecretEncoding::Base32)?.expose(),
+            "GEZDGNBVGY"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn base32_rejects_invalid_characters() {
+        assert!(decode_secret("GEZDGNBV1890", SecretEncoding::Base32).is_err());
+        assert!(decode_secret("not base32!", SecretEncoding::Base32).is_err());
+        assert!(decode_secret("ÄBCD", SecretEncoding::Base32).is_err());
+    }
+
+    #[test]
+    fn empty_secrets_are_rejected() {
+        assert!(decode_secret("", SecretEncoding::Base32).is_err());
+        assert!(decode_secret(" - = ", SecretEncoding::Base32).is_err());
+        assert!(decode_secret("", SecretEncoding::Hex).is_err());
+        assert!(decode_secret("", SecretEncoding::Ascii).is_err());
+    }
+
+    #[test]
+    fn hex_secrets_are_decoded() -> TotpResult<()> {
+        let inputs = [
+            "3132333435363738393031323334353637383930",
+            "0x3132333435363738393031323334353637383930",
+            "31 32 33 34 35 36 37 38 39 30 31 32 33 34 35 36 37 38 39 30",
+            "31:32:33:34:35:36:37:38:39:30:31:32:33:34:35:36:37:38:39:30",
+        ];
+        for input in inputs.iter() {
+            assert_eq!(decode_secret(input, SecretEncoding::Hex)?.expose(), KEY);
+            assert_eq!(
+                normalize_secret(input, SecretEncoding::Hex)?.expose(),
+                BASE32_KEY
+            );
+        }
+
+        assert_eq!(
+            decode_secret("DEADbeef", SecretEncoding::Hex)?.expose(),
+            [0xDE, 0xAD, 0xBE, 0xEF]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn hex_rejects_odd_lengths_and_non_hex_digits() {
+        assert!(decode_secret("123", SecretEncoding::Hex).is_err());
+        assert!(decode_secret("zz", SecretEncoding::Hex).is_err());
+        assert!(decode_secret("+1", SecretEncoding::Hex).is_err());
+    }
+
+    #[test]
+    fn ascii_secrets_are_used_verbatim() -> TotpResult<()> {
+        assert_eq!(
+            decode_secret("12345678901234567890", SecretEncoding::Ascii)?.expose(),
+            KEY
+        );
+        assert_eq!(
+            normalize_secret("12345678901234567890", SecretEncoding::Ascii)?.expose(),
+            BASE32_KEY
+        );
+        assert_eq!(
+            decode_secret("a b-c", SecretEncoding::Ascii)?.expose(),
+            b"a b-c"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn normalized_secrets_decode_to_the_same_key() -> TotpResult<()> {
+        for encoding in [
+            SecretEncoding::Base32,
+            SecretEncoding::Hex,
+            SecretEncoding::Ascii,
+        ]
+        .iter()
+        {
+            let input = match encoding {
+                SecretEncoding::Base32 => "gezd gnbv gy3t qojq",
+                SecretEncoding::Hex => "31323334353637383930",
+                SecretEncoding::Ascii => "1234567890",
+            };
+            let normalized = normalize_secret(input, *encoding)?;
+            assert_eq!(
+                decode_secret(normalized.expose(), SecretEncoding::Base32)?.expose(),
+                decode_secret(input, *encoding)?.expose()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn encodings_parse_from_strings() {
+        assert_eq!(
+            "base32".parse::<SecretEncoding>().ok(),
+            Some(SecretEncoding::Base32)
+        );
+        assert_eq!(
+            "HEX".parse::<SecretEncoding>().ok(),
+            Some(SecretEncoding::Hex)
+        );
+        assert_eq!(
+            "ascii".parse::<SecretEncoding>().ok(),
+            Some(SecretEncoding::Ascii)
+        );
+        assert_eq!(
+            "raw".parse::<SecretEncoding>().ok(),
+            Some(SecretEncoding::Ascii)
+        );
+        assert!("base64".parse::<SecretEncoding>().is_err());
+    }
+}
@@ -1,18 +1,34 @@
 //! OTP — a one time password code generator library
-use config::Config;
+use config::{Config, ListFilter, MetadataUpdate};
 
+#[cfg(unix)]
+pub mod agent;
 pub mod config;
+pub mod encoding;
+mod mac;
+pub mod migrate;
+pub mod ocra;
+pub mod otpauth;
+pub mod passphrase;
+pub mod secret;
 mod secrets;
+#[cfg(feature = "rsa_stoken")]
+pub mod securid;
 pub mod totp;
 
 #[cfg(feature = "rsa_stoken")]
-use crate::config::TotpOptions;
-#[cfg(feature = "rsa_stoken")]
-use stoken::{self, chrono::Utc};
+use stoken::{
+    self,
+    chrono::{TimeZone, Utc},
+};
 
+use crate::config::TotpOptions;
+use crate::encoding::SecretEncoding;
+use crate::secret::SecretString;
+use crate::secrets::Backend;
 use crate::totp::TokenAlgorithm;
-use std::iter::FromIterator;
 use std::path::Path;
+use std::time::Duration;
 use std::{
     error::Error,
     fmt::{Display, Formatter, Result as FmtResult},
@@ -50,50 +66,267 @@ impl Error for TotpConfigError {}
 pub type TotpResult<T> = Result<T, Box<dyn Error>>;
 
 #[cfg(feature = "rsa_stoken")]
-fn stoken(name: &str, options: &TotpOptions) -> TotpResult<String> {
-    let token = stoken::export::import(secrets::get_secret(name, options)?.to_string())
+fn stoken(name: &str, options: &TotpOptions, time_since_epoch: Duration) -> TotpResult<String> {
+    let token = stoken::export::import(secrets::get_secret(name, options)?.expose().to_string())
         .ok_or(TotpError("Unable to import secret as an RSA stoken secret"))?;
-    Ok(stoken::generate(token, Utc::now()))
+    let time = Utc.timestamp(time_since_epoch.as_secs() as i64, 0);
+    Ok(stoken::generate(token, time))
 }
 
+/// Generates the current code for an entry, asking the agent named by `OTPCLI_AGENT_SOCK` for
+/// it when one is running
 pub fn token(name: &str, config: Config) -> TotpResult<String> {
+    token_at_time(name, &config, totp::time_since_epoch())
+}
+
+/// Generates the code for an entry at a specific time since the UNIX epoch, asking the agent
+/// named by `OTPCLI_AGENT_SOCK` for it when one is running
+pub fn token_at_time(
+    name: &str,
+    config: &Config,
+    time_since_epoch: Duration,
+) -> TotpResult<String> {
+    #[cfg(unix)]
+    if let Some(socket) = std::env::var_os(agent::AGENT_SOCK_ENV) {
+        // The agent may have timed out, or not know about entries added after it started
+        if let Ok(code) = agent::request_token_at(socket, name, time_since_epoch) {
+            return Ok(code);
+        }
+    }
+
     let options = config.lookup(name)?;
+    token_at(name, options, time_since_epoch)
+}
 
-    match config.lookup(name)?.algorithm() {
-        TokenAlgorithm::TotpSha1 => totp::standard_totp(name, options),
+/// Generates the codes for several entries at the same time, with each entry's failure kept
+/// with its name instead of aborting the batch.
+///
+/// The master passphrase is only asked for once, however many entries it protects.
+pub fn tokens_at_time(
+    names: &[String],
+    config: &Config,
+    time_since_epoch: Duration,
+) -> Vec<(String, TotpResult<String>)> {
+    names
+        .iter()
+        .map(|name| {
+            let code = token_at_time(name, config, time_since_epoch);
+            (name.clone(), code)
+        })
+        .collect()
+}
+
+/// Generates the code for an entry at a specific time since the UNIX epoch
+pub fn token_at(
+    name: &str,
+    options: &TotpOptions,
+    time_since_epoch: Duration,
+) -> TotpResult<String> {
+    match options.algorithm() {
+        TokenAlgorithm::TotpSha1 => totp::standard_totp_at(name, options, time_since_epoch),
+        TokenAlgorithm::Steam => totp::steam_guard_at(name, options, time_since_epoch),
+        TokenAlgorithm::Motp => totp::motp_at(name, options, time_since_epoch),
+        TokenAlgorithm::Yandex => totp::yandex_at(name, options, time_since_epoch),
+        TokenAlgorithm::Ocra => Err(Box::new(TotpError(
+            "OCRA tokens only generate responses to a challenge",
+        ))),
         #[cfg(feature = "rsa_stoken")]
-        TokenAlgorithm::SToken => stoken(name, options),
+        TokenAlgorithm::SToken => stoken(name, options, time_since_epoch),
     }
 }
 
+/// A secret to add, and how it should be interpreted
+#[derive(Debug, Clone)]
+pub struct SecretInput {
+    /// The secret, or an `otpauth://` or `steam://` URI
+    pub secret: SecretString,
+    pub encoding: SecretEncoding,
+    /// The token type, defaulting to the one implied by a URI or standard TOTP otherwise
+    pub algorithm: Option<TokenAlgorithm>,
+    /// The PIN of token types that need one, or a static PIN or password to combine with the
+    /// codes of other tokens
+    pub pin: Option<SecretString>,
+    /// The OCRA suite of challenge-response tokens, which implies the OCRA token type
+    pub ocra_suite: Option<String>,
+}
+
+/// Adds a TOTP secret, which may also be given as an `otpauth://` or `steam://` URI.
+///
+/// The issuer and account of a URI are used unless `metadata` overrides them. mOTP secrets are
+/// always used as text, whatever their encoding.
 pub fn add_totp_secret<P: AsRef<Path>>(
     config: Config,
     config_dir: P,
     name: &str,
-    secret: String,
+    input: SecretInput,
+    metadata: MetadataUpdate,
 ) -> TotpResult<()> {
-    base32::decode(base32::Alphabet::RFC4648 { padding: false }, &secret)
-        .expect("Invalid base32 OTP secret");
+    add_totp_secret_with(
+        &secrets::Secrets,
+        config,
+        config_dir.as_ref(),
+        name,
+        input,
+        metadata,
+    )
+}
 
-    add_secret(&config, config_dir, name, secret, TokenAlgorithm::TotpSha1).map(|_| ())
+fn add_totp_secret_with(
+    backend: &dyn Backend,
+    config: Config,
+    config_dir: &Path,
+    name: &str,
+    input: SecretInput,
+    metadata: MetadataUpdate,
+) -> TotpResult<()> {
+    let (secret, algorithm, metadata) = if otpauth::is_uri(input.secret.expose()) {
+        let uri = otpauth::OtpAuthUri::parse(input.secret.expose())?;
+        if !uri.has_default_parameters() {
+            return Err(Box::new(TotpConfigError(format!(
+                "Only SHA1 codes with 6 digits and a 30 second period can be added, not {} codes \
+                 with {} digits and a {} second period",
+                uri.hash,
+                uri.digits,
+                uri.period.as_secs()
+            ))));
+        }
+        let metadata = MetadataUpdate {
+            issuer: metadata.issuer.or(uri.issuer),
+            account: metadata.account.or(uri.account),
+            ..metadata
+        };
+        let secret = encoding::normalize_secret(&uri.secret, SecretEncoding::Base32)?;
+        (secret, input.algorithm.unwrap_or(uri.algorithm), metadata)
+    } else {
+        let algorithm = match (input.algorithm, &input.ocra_suite) {
+            (Some(algorithm), _) => algorithm,
+            (None, Some(_)) => TokenAlgorithm::Ocra,
+            (None, None) => TokenAlgorithm::TotpSha1,
+        };
+        let encoding = match algorithm {
+            TokenAlgorithm::Motp => SecretEncoding::Ascii,
+            _ => input.encoding,
+        };
+        let secret = encoding::normalize_secret(input.secret.expose(), encoding)?;
+        (secret, algorithm, metadata)
+    };
+
+    let suite = match (algorithm, input.ocra_suite) {
+        (TokenAlgorithm::Ocra, Some(suite)) => Some(suite.parse::<ocra::OcraSuite>()?),
+        (TokenAlgorithm::Ocra, None) => {
+            return Err(Box::new(TotpError("OCRA tokens need an OCRA suite")))
+        }
+        (_, Some(_)) => {
+            return Err(Box::new(TotpConfigError(format!(
+                "{} tokens don't use an OCRA suite",
+                algorithm
+            ))))
+        }
+        (_, None) => None,
+    };
+
+    let needs_pin = match &suite {
+        Some(suite) => suite.uses_pin(),
+        None => algorithm.needs_pin(),
+    };
+    let pin = match (needs_pin, input.pin) {
+        (true, Some(pin)) if !pin.is_empty() => Some(pin),
+        (true, _) => {
+            return Err(Box::new(TotpConfigError(format!(
+                "{} tokens need a PIN",
+                algorithm
+            ))))
+        }
+        // Only combined with the codes, for logins that expect both in one field
+        (false, Some(pin)) if !pin.is_empty() => Some(pin),
+        (false, _) => None,
+    };
+
+    let replaced = config.lookup(name).ok().cloned();
+    let overwritten = replaced
+        .as_ref()
+        .and_then(|replaced| OverwrittenSecret::read(backend, name, replaced));
+    let mut config = insert_secret(backend, &config, name, secret, algorithm)?;
+    let options = config.lookup_mut(name)?;
+    options.set_ocra_suite(suite.map(|suite| suite.to_string()));
+    options.update_metadata(metadata);
+    let stored = pin
+        .map_or(Ok(()), |pin| backend.store_pin(name, options, &pin))
+        .and_then(|()| config::save_config(config_dir, &config));
+    if let Err(e) = stored {
+        // The failure to add the secret matters more than one to clean up after it
+        if let Ok(added) = config.lookup(name) {
+            let _ = match overwritten {
+                Some(overwritten) => overwritten.restore(backend, name, added),
+                None => backend.remove_secret(name, added),
+            };
+        }
+        return Err(e);
+    }
+    remove_replaced_pin(name, replaced, &config);
+    Ok(())
+}
+
+/// The secret and PIN of an entry that adding a secret under the same name writes over, which are
+/// put back when the add fails
+struct OverwrittenSecret {
+    options: TotpOptions,
+    secret: SecretString,
+    pin: Option<SecretString>,
+}
+
+impl OverwrittenSecret {
+    /// Reads the secret of `replaced` if a new one would be stored in its place
+    fn read(backend: &dyn Backend, name: &str, replaced: &TotpOptions) -> Option<Self> {
+        let location = replaced
+            .storage()
+            .unwrap_or(&config::SecretLocation::Config);
+        if *location == config::SecretLocation::Config || *location != secrets::default_location() {
+            return None;
+        }
+        Some(OverwrittenSecret {
+            options: replaced.clone(),
+            secret: backend.get_secret(name, replaced).ok()?,
+            pin: backend.get_pin(name, replaced).ok()?,
+        })
+    }
+
+    fn restore(self, backend: &dyn Backend, name: &str, added: &TotpOptions) -> TotpResult<()> {
+        let location = self
+            .options
+            .storage()
+            .unwrap_or(&config::SecretLocation::Config);
+        let mut restored =
+            backend.store_secret_at(location, name, &self.secret, self.options.algorithm())?;
+        match self.pin {
+            Some(pin) => backend.store_pin(name, &mut restored, &pin),
+            None => backend.remove_replaced_pin(name, added, &self.options),
+        }
+    }
 }
 
-#[cfg(feature = "ras_stoken")]
+/// Imports an RSA SecurID token from an `.sdtid` file, or from a CTF token string or URI
+#[cfg(feature = "rsa_stoken")]
 pub fn add_stoken<P: AsRef<Path>>(
     config: &Config,
     config_dir: P,
     name: &str,
-    rsa_token_file: P,
-    pin: &str,
+    token: &str,
+    options: &securid::ImportOptions,
 ) -> TotpResult<()> {
-    let token = stoken::read_file(rsa_token_file);
-    let token = stoken::RSAToken::from_xml(token, pin);
-    let exported_token = stoken::export::export(token).expect("Unable to export RSA Token");
+    let contents = if Path::new(token).is_file() {
+        std::fs::read_to_string(token)?
+    } else {
+        token.to_string()
+    };
+    let token = securid::read_token(&contents, options)?;
+    let exported_token =
+        stoken::export::export(token).ok_or(TotpError("Unable to export RSA Token"))?;
     add_secret(
-        &config,
+        config,
         config_dir,
         name,
-        exported_token,
+        SecretString::new(exported_token),
         TokenAlgorithm::SToken,
     )?;
 
@@ -104,53 +337,542 @@ pub fn add_secret<P: AsRef<Path>>(
     config: &Config,
     config_dir: P,
     name: &str,
-    secret: String,
+    secret: SecretString,
+    algorithm: TokenAlgorithm,
+) -> TotpResult<Config> {
+    let replaced = config.lookup(name).ok().cloned();
+    let config = insert_secret(&secrets::Secrets, config, name, secret, algorithm)?;
+    config::save_config(config_dir, &config)?;
+    remove_replaced_pin(name, replaced, &config);
+    Ok(config)
+}
+
+/// Removes the keychain PIN of an entry that was replaced by one without a PIN. The entry is
+/// already saved by then, so a failure is left for `gc` to clean up rather than reported
+fn remove_replaced_pin(name: &str, replaced: Option<TotpOptions>, config: &Config) {
+    if let (Some(replaced), Ok(options)) = (replaced, config.lookup(name)) {
+        let _ = secrets::remove_replaced_pin(name, &replaced, options);
+    }
+}
+
+fn insert_secret(
+    backend: &dyn Backend,
+    config: &Config,
+    name: &str,
+    secret: SecretString,
     algorithm: TokenAlgorithm,
 ) -> TotpResult<Config> {
-    let totp_options = secrets::store_secret(&name, &secret, algorithm)?;
+    let mut totp_options =
+        backend.store_secret_at(&secrets::default_location(), name, &secret, algorithm)?;
+    passphrase::seal_entry_like(config, name, &mut totp_options)?;
+    if let Ok(existing) = config.lookup(name) {
+        totp_options.copy_metadata_from(existing);
+    }
     let mut config: Config = config.clone();
     config.insert(name.to_string(), totp_options);
-    let string = toml::to_string(&config)?;
+    Ok(config)
+}
 
-    config::ensure_config_dir(&config_dir)?;
+/// Re-encrypts every config-stored secret and PIN with `new_passphrase`, or stores them
+/// unencrypted again when it is `None`
+pub fn change_passphrase<P: AsRef<Path>>(
+    config: Config,
+    config_dir: P,
+    new_passphrase: Option<SecretString>,
+) -> TotpResult<Config> {
+    let sealer = new_passphrase
+        .as_ref()
+        .map(passphrase::Sealer::new)
+        .transpose()?;
+    let config = reseal_config(config, config_dir.as_ref(), sealer.as_ref())?;
 
-    std::fs::write(config_dir.as_ref().join("config.toml"), string)?;
+    if let Some(new_passphrase) = new_passphrase {
+        passphrase::remember(new_passphrase);
+    }
+    Ok(config)
+}
+
+/// Decrypts every config-stored secret and PIN and encrypts them again with `sealer`, if any
+fn reseal_config(
+    mut config: Config,
+    config_dir: &Path,
+    sealer: Option<&passphrase::Sealer>,
+) -> TotpResult<Config> {
+    let names: Vec<String> = config.codes().keys().cloned().collect();
+    for name in names {
+        let options = config.lookup_mut(&name)?;
+        passphrase::unseal_entry(&name, options)?;
+        if let Some(sealer) = sealer {
+            passphrase::seal_entry(&name, options, sealer)?;
+        }
+    }
+    config::save_config(config_dir, &config)?;
     Ok(config)
 }
 
-pub fn list_secrets(config: Config, _prefix: Option<String>) -> TotpResult<Vec<String>> {
-    Ok(Vec::from_iter(config.codes().keys().cloned()))
+/// Updates the issuer, account, tags or notes of an existing entry
+pub fn edit_secret<P: AsRef<Path>>(
+    mut config: Config,
+    config_dir: P,
+    name: &str,
+    update: MetadataUpdate,
+) -> TotpResult<Config> {
+    config.lookup_mut(name)?.update_metadata(update);
+    config::save_config(config_dir, &config)?;
+    Ok(config)
+}
+
+/// Stores a new PIN or password for `name`, in the same backend as its secret
+pub fn set_pin<P: AsRef<Path>>(
+    mut config: Config,
+    config_dir: P,
+    name: &str,
+    pin: &SecretString,
+) -> TotpResult<Config> {
+    if pin.is_empty() {
+        return Err(Box::new(TotpError("The PIN can't be empty")));
+    }
+    secrets::store_pin(name, config.lookup_mut(name)?, pin)?;
+    config::save_config(config_dir, &config)?;
+    Ok(config)
+}
+
+/// Combines a code generated for `name` with its stored PIN or password, before or after the
+/// code as its entry says, for logins like VPNs that expect both in one field
+pub fn with_pin(name: &str, config: &Config, code: &str) -> TotpResult<String> {
+    let pin = stored_pin(name, config)?.ok_or_else(|| {
+        TotpConfigError(format!("No PIN is stored for the config named '{}'", name))
+    })?;
+    Ok(config
+        .lookup(name)?
+        .pin_position()
+        .combine(pin.expose(), code))
+}
+
+/// The PIN or password stored for `name`, if it has one, asking the agent named by
+/// `OTPCLI_AGENT_SOCK` for it when one is running
+pub fn stored_pin(name: &str, config: &Config) -> TotpResult<Option<SecretString>> {
+    #[cfg(unix)]
+    if let Some(socket) = std::env::var_os(agent::AGENT_SOCK_ENV) {
+        // The agent can hold an older copy of the entry, so only trust it when it has a PIN
+        if let Ok(Some(pin)) = agent::request_pin(socket, name) {
+            return Ok(Some(pin));
+        }
+    }
+
+    secrets::get_pin(name, config.lookup(name)?)
+}
+
+/// Records that codes were generated for `names`, updating their `last_used_at` timestamps
+/// without rewriting the config
+pub fn record_usage<P: AsRef<Path>>(config_dir: P, names: &[&str]) -> TotpResult<()> {
+    config::save_usage(config_dir, names)
+}
+
+/// Lists the names of all entries matching `filter`, sorted by name
+pub fn list_secrets(config: &Config, filter: &ListFilter) -> TotpResult<Vec<String>> {
+    let mut names: Vec<String> = config
+        .codes()
+        .iter()
+        .filter(|(name, options)| filter.matches(name, options))
+        .map(|(name, _)| name.clone())
+        .collect();
+    names.sort();
+    Ok(names)
 }
 
+/// Duplicates the entry `src` (including its stored secret) as `dst`
+pub fn copy_secret<P: AsRef<Path>>(
+    config: Config,
+    config_dir: P,
+    src: &str,
+    dst: &str,
+) -> TotpResult<Config> {
+    let mut new_config = duplicate_entry(&config, src, dst)?;
+    new_config.lookup_mut(dst)?.mark_created();
+    let new_options = new_config.lookup(dst)?;
+
+    if let Err(e) = config::save_config(config_dir, &new_config) {
+        // The failure to save matters more than one to clean up after it
+        let _ = secrets::remove_secret(dst, new_options);
+        return Err(e);
+    }
+
+    Ok(new_config)
+}
+
+/// Renames the entry `src` (including its stored secret) to `dst`
+pub fn rename_secret<P: AsRef<Path>>(
+    config: Config,
+    config_dir: P,
+    src: &str,
+    dst: &str,
+) -> TotpResult<Config> {
+    let mut new_config = duplicate_entry(&config, src, dst)?;
+    new_config.remove(src);
+    let new_options = new_config.lookup(dst)?;
+
+    // Failures to roll back are ignored, so the original failure is the one reported
+    if let Err(e) = config::save_config(&config_dir, &new_config) {
+        let _ = secrets::remove_secret(dst, new_options);
+        return Err(e);
+    }
+
+    if let Err(e) = secrets::remove_secret(src, config.lookup(src)?) {
+        let _ = config::save_config(&config_dir, &config);
+        let _ = secrets::remove_secret(dst, new_options);
+        return Err(e);
+    }
+
+    Ok(new_config)
+}
+
+fn duplicate_entry(config: &Config, src: &str, dst: &str) -> TotpResult<Config> {
+    let options = config.lookup(src)?;
+    if config.lookup(dst).is_ok() {
+        return Err(Box::new(TotpConfigError(format!(
+            "A config named '{}' already exists",
+            dst
+        ))));
+    }
+
+    let new_options = secrets::copy_secret(src, dst, options)?;
+    let mut config = config.clone();
+    config.insert(dst.to_string(), new_options);
+    Ok(config)
+}
+
+/// Deletes the entry `name` from the config along with its stored secret
 pub fn delete_secret<P: AsRef<Path>>(
     mut config: Config,
     config_dir: P,
     name: String,
 ) -> TotpResult<()> {
+    let options = config.lookup(&name)?.clone();
     config.remove(&name);
-    let string = toml::to_string(&config).expect("unable to write config to TOML");
-    config::ensure_config_dir(&config_dir)?;
-    std::fs::write(config_dir.as_ref().join("config.toml"), string)?;
-    Ok(())
+    config::save_config(config_dir, &config)?;
+    secrets::remove_secret(&name, &options)
+}
+
+/// Finds secrets stored in the keychain that no config entry refers to anymore
+#[cfg(feature = "keychain")]
+pub fn orphaned_keychain_secrets(config: &Config) -> TotpResult<Vec<String>> {
+    use config::SecretLocation;
+
+    let mut orphans: Vec<String> = secrets::keychain_names()?
+        .into_iter()
+        .filter(|name| match config.lookup(name) {
+            Ok(options) => !matches!(options.storage(), Some(SecretLocation::KeyChain)),
+            Err(_) => true,
+        })
+        .collect();
+    orphans.sort();
+    orphans.dedup();
+    Ok(orphans)
+}
+
+/// Removes a keychain secret that is no longer referenced by the config
+#[cfg(feature = "keychain")]
+pub fn remove_orphaned_keychain_secret(name: &str) -> TotpResult<()> {
+    secrets::remove_keychain_secret(name)
 }
 
+/// Moves every secret into the keychain, failing on the first account that could not be moved
 #[cfg(feature = "keychain")]
+#[deprecated(note = "use migrate::migrate_secrets, which reports the outcome for each account")]
 pub fn migrate_secrets_to_keychain<P: AsRef<Path>>(
     config: Config,
     config_dir: P,
 ) -> TotpResult<Config> {
-    let mut new_codes = config.clone();
-    for (name, value) in config.codes().iter() {
-        println!("Migrating {}", name);
-        let secret = secrets::get_secret(name, value)?;
-        new_codes = add_secret(
-            &new_codes,
-            config_dir.as_ref(),
-            name,
-            secret,
-            value.algorithm(),
-        )?;
-    }
-
-    Ok(new_codes)
+    use config::SecretLocation;
+    use migrate::MigrationStatus;
+
+    let (config, report) =
+        migrate::migrate_secrets(config, config_dir, &SecretLocation::KeyChain, &[])?;
+    match report
+        .accounts
+        .into_iter()
+        .find(|(_, status)| matches!(status, MigrationStatus::Failed(_)))
+    {
+        Some((name, status)) => Err(Box::new(TotpConfigError(format!("{}: {}", name, status)))),
+        None => Ok(config),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    /// A config directory of a test's own, removed when dropped
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(test: &str) -> Self {
+            TestDir(std::env::temp_dir().join(format!(
+                "otpcli-lib-test-{}-{}",
+                test,
+                std::process::id()
+            )))
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn config() -> Config {
+        toml::from_str(&format!(
+            r#"
+            [totp.github]
+            storage = "config"
+            secret = "{secret}"
+            issuer = "GitHub"
+            tags = ["work"]
+            created_at = 1
+
+            [totp.gitlab]
+            storage = "config"
+            secret = "{secret}"
+            issuer = "GitLab"
+            "#,
+            secret = SECRET
+        ))
+        .expect("a valid config")
+    }
+
+    /// The test config, saved to `dir` with a code generated for `github`
+    fn used_config(dir: &TestDir) -> TotpResult<Config> {
+        config::save_config(&dir.0, &config())?;
+        record_usage(&dir.0, &["github"])?;
+        Ok(config::load_config(&dir.0)?)
+    }
+
+    #[test]
+    fn renamed_entries_keep_their_secret_and_metadata() -> TotpResult<()> {
+        let dir = TestDir::new("rename");
+        let config = used_config(&dir)?;
+        let last_used_at = config.lookup("github")?.last_used_at();
+        assert!(last_used_at.is_some());
+        let config = rename_secret(config, &dir.0, "github", "gh")?;
+
+        assert!(config.lookup("github").is_err());
+        let options = config.lookup("gh")?;
+        assert_eq!(options.secret().map(SecretString::expose), Some(SECRET));
+        assert_eq!(options.issuer().map(String::as_str), Some("GitHub"));
+        assert_eq!(options.created_at(), Some(1));
+        assert_eq!(options.last_used_at(), last_used_at);
+        let saved = config::load_config(&dir.0)?;
+        assert_eq!(saved.lookup("gh")?.last_used_at(), last_used_at);
+        Ok(())
+    }
+
+    #[test]
+    fn copies_keep_the_source_and_start_their_own_timestamps() -> TotpResult<()> {
+        let dir = TestDir::new("copy");
+        let config = copy_secret(used_config(&dir)?, &dir.0, "github", "gh")?;
+
+        assert_eq!(config.lookup("github")?.created_at(), Some(1));
+        assert!(config.lookup("github")?.last_used_at().is_some());
+        let options = config.lookup("gh")?;
+        assert_eq!(options.secret().map(SecretString::expose), Some(SECRET));
+        assert_eq!(options.tags(), ["work"]);
+        assert!(options.created_at() > Some(1));
+        assert_eq!(options.last_used_at(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn edits_update_and_save_the_metadata_of_an_entry() -> TotpResult<()> {
+        let dir = TestDir::new("edit");
+        let update = MetadataUpdate {
+            account: Some("alice".to_string()),
+            remove_tags: vec!["work".to_string()],
+            ..MetadataUpdate::default()
+        };
+        edit_secret(config(), &dir.0, "github", update)?;
+
+        let saved = config::load_config(&dir.0)?;
+        let options = saved.lookup("github")?;
+        assert_eq!(options.account().map(String::as_str), Some("alice"));
+        assert_eq!(options.issuer().map(String::as_str), Some("GitHub"));
+        assert!(options.tags().is_empty());
+        assert!(edit_secret(config(), &dir.0, "missing", MetadataUpdate::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn deleting_removes_the_entry_from_the_saved_config() -> TotpResult<()> {
+        let dir = TestDir::new("delete");
+        delete_secret(config(), &dir.0, "github".to_string())?;
+
+        let saved = config::load_config(&dir.0)?;
+        assert!(saved.lookup("github").is_err());
+        assert!(saved.lookup("gitlab").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn deleting_an_unknown_name_fails_without_saving() {
+        let dir = TestDir::new("delete-unknown");
+        assert!(delete_secret(config(), &dir.0, "missing".to_string()).is_err());
+        assert!(!dir.0.join("config.toml").exists());
+    }
+
+    #[cfg(feature = "keychain")]
+    #[test]
+    fn deleting_a_keychain_entry_reports_a_secret_missing_from_the_keychain() -> TotpResult<()> {
+        let dir = TestDir::new("delete-keychain");
+        let name = format!("otpcli-delete-test-{}", std::process::id());
+        let mut config = config();
+        config.insert(
+            name.clone(),
+            toml::from_str(r#"storage = "keychain""#).expect("a valid entry"),
+        );
+
+        // The entry is still removed from the config, with the keychain's error reported
+        assert!(delete_secret(config, &dir.0, name.clone()).is_err());
+        assert!(config::load_config(&dir.0)?.lookup(&name).is_err());
+        Ok(())
+    }
+
+    /// A fake keychain holding `github`'s secret and PIN, with a config entry pointing at them
+    #[cfg(feature = "keychain")]
+    fn keychain_config(keychain: &secrets::FakeKeychain) -> Config {
+        keychain
+            .secrets
+            .borrow_mut()
+            .insert("github".to_string(), SECRET.into());
+        keychain
+            .pins
+            .borrow_mut()
+            .insert("github".to_string(), "1234".into());
+        let mut config = config();
+        config.insert(
+            "github".to_string(),
+            toml::from_str("storage = \"keychain\"\nkeychain_pin = true").expect("a valid entry"),
+        );
+        config
+    }
+
+    #[cfg(feature = "keychain")]
+    fn readded(pin: &str) -> SecretInput {
+        SecretInput {
+            secret: "JBSWY3DPEHPK3PXP".into(),
+            encoding: SecretEncoding::Base32,
+            algorithm: None,
+            pin: Some(pin.into()),
+            ocra_suite: None,
+        }
+    }
+
+    #[cfg(feature = "keychain")]
+    #[test]
+    fn failing_to_store_the_pin_of_a_readded_entry_keeps_the_old_secret() -> TotpResult<()> {
+        let dir = TestDir::new("readd-pin");
+        let keychain = secrets::FakeKeychain {
+            fail_pins: true,
+            ..secrets::FakeKeychain::default()
+        };
+        let config = keychain_config(&keychain);
+        let result = add_totp_secret_with(
+            &keychain,
+            config,
+            &dir.0,
+            "github",
+            readded("5678"),
+            MetadataUpdate::default(),
+        );
+
+        assert!(result.is_err());
+        assert!(!dir.0.join("config.toml").exists());
+        assert_eq!(keychain.secrets.borrow()["github"], SECRET.into());
+        assert_eq!(keychain.pins.borrow()["github"], "1234".into());
+        Ok(())
+    }
+
+    #[cfg(feature = "keychain")]
+    #[test]
+    fn failing_to_save_a_readded_entry_restores_the_old_secret_and_pin() -> TotpResult<()> {
+        // A file where the config directory should be makes saving fail
+        let dir = TestDir::new("readd-save");
+        std::fs::write(&dir.0, "")?;
+        let keychain = secrets::FakeKeychain::default();
+        let config = keychain_config(&keychain);
+        let result = add_totp_secret_with(
+            &keychain,
+            config,
+            &dir.0,
+            "github",
+            readded("5678"),
+            MetadataUpdate::default(),
+        );
+        let _ = std::fs::remove_file(&dir.0);
+
+        assert!(result.is_err());
+        assert_eq!(keychain.secrets.borrow()["github"], SECRET.into());
+        assert_eq!(keychain.pins.borrow()["github"], "1234".into());
+        Ok(())
+    }
+
+    #[cfg(feature = "keychain")]
+    #[test]
+    fn failing_to_add_a_new_entry_removes_its_secret() -> TotpResult<()> {
+        let dir = TestDir::new("add-pin");
+        let keychain = secrets::FakeKeychain {
+            fail_pins: true,
+            ..secrets::FakeKeychain::default()
+        };
+        let result = add_totp_secret_with(
+            &keychain,
+            config(),
+            &dir.0,
+            "new",
+            readded("5678"),
+            MetadataUpdate::default(),
+        );
+
+        assert!(result.is_err());
+        assert!(keychain.secrets.borrow().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn pins_are_saved_and_combined_with_codes() -> TotpResult<()> {
+        let dir = TestDir::new("pin");
+        assert!(set_pin(config(), &dir.0, "github", &"".into()).is_err());
+        assert!(set_pin(config(), &dir.0, "missing", &"hunter2".into()).is_err());
+        assert!(with_pin("github", &config(), "123456").is_err());
+
+        set_pin(config(), &dir.0, "github", &"hunter2".into())?;
+        let mut saved = config::load_config(&dir.0)?;
+        assert_eq!(
+            stored_pin("github", &saved)?
+                .as_ref()
+                .map(SecretString::expose),
+            Some("hunter2")
+        );
+        assert_eq!(with_pin("github", &saved, "123456")?, "hunter2123456");
+
+        saved.lookup_mut("github")?.update_metadata(MetadataUpdate {
+            pin_position: Some(config::PinPosition::Suffix),
+            ..MetadataUpdate::default()
+        });
+        assert_eq!(with_pin("github", &saved, "123456")?, "123456hunter2");
+        Ok(())
+    }
+
+    #[test]
+    fn existing_destinations_and_unknown_sources_are_refused() {
+        let dir = TestDir::new("refused");
+        assert!(rename_secret(config(), &dir.0, "github", "gitlab").is_err());
+        assert!(copy_secret(config(), &dir.0, "github", "gitlab").is_err());
+        assert!(rename_secret(config(), &dir.0, "missing", "new").is_err());
+        assert!(copy_secret(config(), &dir.0, "missing", "new").is_err());
+        assert!(!dir.0.join("config.toml").exists());
+    }
 }
@@ -0,0 +1,241 @@
+//! The HMAC and hash primitives every code is generated with.
+//!
+//! The implementation is picked with cargo features: `ring` is preferred, then `rustcrypto`
+//! (the RustCrypto `hmac`/`sha1`/`sha2` crates), then `rust-crypto`. None of their types are
+//! part of the public API.
+use crate::totp::HashAlgorithm;
+
+#[cfg(not(any(feature = "ring", feature = "rustcrypto", feature = "rust-crypto")))]
+compile_error!("otp needs one of the `ring`, `rustcrypto` or `rust-crypto` features enabled");
+
+/// A library implementing the primitives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+// Only the selected backend is used outside of tests
+#[allow(dead_code)]
+pub(crate) enum Backend {
+    #[cfg(feature = "rust-crypto")]
+    LegacyRustCrypto,
+    #[cfg(feature = "rustcrypto")]
+    RustCrypto,
+    #[cfg(feature = "ring")]
+    Ring,
+}
+
+impl Backend {
+    #[cfg(feature = "ring")]
+    pub(crate) const SELECTED: Backend = Backend::Ring;
+    #[cfg(all(feature = "rustcrypto", not(feature = "ring")))]
+    pub(crate) const SELECTED: Backend = Backend::RustCrypto;
+    #[cfg(all(
+        feature = "rust-crypto",
+        not(any(feature = "ring", feature = "rustcrypto"))
+    ))]
+    pub(crate) const SELECTED: Backend = Backend::LegacyRustCrypto;
+
+    /// Every backend compiled into this build
+    #[cfg(test)]
+    pub(crate) const ALL: &'static [Backend] = &[
+        #[cfg(feature = "rust-crypto")]
+        Backend::LegacyRustCrypto,
+        #[cfg(feature = "rustcrypto")]
+        Backend::RustCrypto,
+        #[cfg(feature = "ring")]
+        Backend::Ring,
+    ];
+
+    pub(crate) fn hmac(self, algorithm: HashAlgorithm, key: &[u8], message: &[u8]) -> Vec<u8> {
+        match self {
+            #[cfg(feature = "rust-crypto")]
+            Backend::LegacyRustCrypto => legacy_rust_crypto::hmac(algorithm, key, message),
+            #[cfg(feature = "rustcrypto")]
+            Backend::RustCrypto => rustcrypto::hmac(algorithm, key, message),
+            #[cfg(feature = "ring")]
+            Backend::Ring => with_ring::hmac(algorithm, key, message),
+        }
+    }
+
+    pub(crate) fn digest(self, algorithm: HashAlgorithm, message: &[u8]) -> Vec<u8> {
+        match self {
+            #[cfg(feature = "rust-crypto")]
+            Backend::LegacyRustCrypto => legacy_rust_crypto::digest(algorithm, message),
+            #[cfg(feature = "rustcrypto")]
+            Backend::RustCrypto => rustcrypto::digest(algorithm, message),
+            #[cfg(feature = "ring")]
+            Backend::Ring => with_ring::digest(algorithm, message),
+        }
+    }
+
+    /// MD5, which ring doesn't provide, so it comes from RustCrypto's `md-5` there
+    pub(crate) fn md5(self, message: &[u8]) -> Vec<u8> {
+        match self {
+            #[cfg(feature = "rust-crypto")]
+            Backend::LegacyRustCrypto => legacy_rust_crypto::md5(message),
+            #[cfg(feature = "rustcrypto")]
+            Backend::RustCrypto => md5(message),
+            #[cfg(feature = "ring")]
+            Backend::Ring => md5(message),
+        }
+    }
+}
+
+/// Compares two MACs (or codes derived from them) in time that only depends on their length
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(feature = "rust-crypto")]
+mod legacy_rust_crypto {
+    use crypto::{
+        digest::Digest, hmac::Hmac, mac::Mac, md5::Md5, sha1::Sha1, sha2::Sha256, sha2::Sha512,
+    };
+
+    use crate::totp::HashAlgorithm;
+
+    fn hmac_with<D: Digest>(digest: D, key: &[u8], message: &[u8]) -> Vec<u8> {
+        let mut hmac = Hmac::new(digest, key);
+        hmac.input(message);
+        hmac.result().code().to_vec()
+    }
+
+    fn digest_with<D: Digest>(mut digest: D, message: &[u8]) -> Vec<u8> {
+        digest.input(message);
+        let mut out = vec![0; digest.output_bytes()];
+        digest.result(&mut out);
+        out
+    }
+
+    pub fn hmac(algorithm: HashAlgorithm, key: &[u8], message: &[u8]) -> Vec<u8> {
+        match algorithm {
+            HashAlgorithm::Sha1 => hmac_with(Sha1::new(), key, message),
+            HashAlgorithm::Sha256 => hmac_with(Sha256::new(), key, message),
+            HashAlgorithm::Sha512 => hmac_with(Sha512::new(), key, message),
+        }
+    }
+
+    pub fn digest(algorithm: HashAlgorithm, message: &[u8]) -> Vec<u8> {
+        match algorithm {
+            HashAlgorithm::Sha1 => digest_with(Sha1::new(), message),
+            HashAlgorithm::Sha256 => digest_with(Sha256::new(), message),
+            HashAlgorithm::Sha512 => digest_with(Sha512::new(), message),
+        }
+    }
+
+    pub fn md5(message: &[u8]) -> Vec<u8> {
+        digest_with(Md5::new(), message)
+    }
+}
+
+#[cfg(feature = "rustcrypto")]
+mod rustcrypto {
+    use hmac::{Hmac, Mac};
+    use sha2::Digest;
+
+    use crate::totp::HashAlgorithm;
+
+    macro_rules! hmac_with {
+        ($digest:ty, $key:expr, $message:expr) => {{
+            let mut hmac =
+                Hmac::<$digest>::new_from_slice($key).expect("HMAC accepts keys of any length");
+            hmac.update($message);
+            hmac.finalize().into_bytes().to_vec()
+        }};
+    }
+
+    pub fn hmac(algorithm: HashAlgorithm, key: &[u8], message: &[u8]) -> Vec<u8> {
+        match algorithm {
+            HashAlgorithm::Sha1 => hmac_with!(sha1::Sha1, key, message),
+            HashAlgorithm::Sha256 => hmac_with!(sha2::Sha256, key, message),
+            HashAlgorithm::Sha512 => hmac_with!(sha2::Sha512, key, message),
+        }
+    }
+
+    pub fn digest(algorithm: HashAlgorithm, message: &[u8]) -> Vec<u8> {
+        match algorithm {
+            HashAlgorithm::Sha1 => sha1::Sha1::digest(message).to_vec(),
+            HashAlgorithm::Sha256 => sha2::Sha256::digest(message).to_vec(),
+            HashAlgorithm::Sha512 => sha2::Sha512::digest(message).to_vec(),
+        }
+    }
+}
+
+#[cfg(any(feature = "rustcrypto", feature = "ring"))]
+fn md5(message: &[u8]) -> Vec<u8> {
+    use md5::{Digest, Md5};
+
+    Md5::digest(message).to_vec()
+}
+
+#[cfg(feature = "ring")]
+mod with_ring {
+    use ring::{digest, hmac};
+
+    use crate::totp::HashAlgorithm;
+
+    pub fn hmac(algorithm: HashAlgorithm, key: &[u8], message: &[u8]) -> Vec<u8> {
+        let algorithm = match algorithm {
+            HashAlgorithm::Sha1 => hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            HashAlgorithm::Sha256 => hmac::HMAC_SHA256,
+            HashAlgorithm::Sha512 => hmac::HMAC_SHA512,
+        };
+        hmac::sign(&hmac::Key::new(algorithm, key), message)
+            .as_ref()
+            .to_vec()
+    }
+
+    pub fn digest(algorithm: HashAlgorithm, message: &[u8]) -> Vec<u8> {
+        let algorithm = match algorithm {
+            HashAlgorithm::Sha1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            HashAlgorithm::Sha256 => &digest::SHA256,
+            HashAlgorithm::Sha512 => &digest::SHA512,
+        };
+        digest::digest(algorithm, message).as_ref().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backends_agree_on_hmacs_and_digests() {
+        let algorithms = [
+            HashAlgorithm::Sha1,
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha512,
+        ];
+        for backend in Backend::ALL {
+            for algorithm in algorithms.iter() {
+                assert_eq!(
+                    backend.hmac(*algorithm, b"key", b"message"),
+                    Backend::SELECTED.hmac(*algorithm, b"key", b"message")
+                );
+                assert_eq!(
+                    backend.digest(*algorithm, b"message"),
+                    Backend::SELECTED.digest(*algorithm, b"message")
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn md5_matches_rfc1321() {
+        // https://tools.ietf.org/html/rfc1321#appendix-A.5
+        for backend in Backend::ALL {
+            assert_eq!(
+                backend.md5(b"abc"),
+                [
+                    0x90, 0x01, 0x50, 0x98, 0x3c, 0xd2, 0x4f, 0xb0, 0xd6, 0x96, 0x3f, 0x7d, 0x28,
+                    0xe1, 0x7f, 0x72
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn constant_time_eq_compares_contents_and_lengths() {
+        assert!(constant_time_eq(b"123456", b"123456"));
+        assert!(!constant_time_eq(b"123456", b"123457"));
+        assert!(!constant_time_eq(b"123456", b"12345"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -0,0 +1,397 @@
+//! Moves secrets between the storage backends supported by otpcli.
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::path::Path;
+
+use std::time::Duration;
+
+use crate::config::{self, Config, SecretLocation, TotpOptions};
+use crate::ocra::{self, OcraInput};
+use crate::secrets::Backend;
+use crate::totp::TokenAlgorithm;
+use crate::{passphrase, secrets, token_at, totp, TotpConfigError, TotpResult};
+
+/// What happened to a single account during a migration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MigrationStatus {
+    /// The secret was moved and generates the same code in its new location
+    Migrated,
+    /// The secret was already stored in the requested location
+    AlreadyMigrated,
+    /// The secret was left where it was
+    Failed(String),
+}
+
+impl Display for MigrationStatus {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            MigrationStatus::Migrated => write!(f, "migrated"),
+            MigrationStatus::AlreadyMigrated => write!(f, "already migrated"),
+            MigrationStatus::Failed(reason) => write!(f, "failed: {}", reason),
+        }
+    }
+}
+
+/// The outcome of a migration, one entry per account that was considered
+#[derive(Debug, Clone, Default)]
+pub struct MigrationReport {
+    pub accounts: Vec<(String, MigrationStatus)>,
+}
+
+impl MigrationReport {
+    pub fn has_failures(&self) -> bool {
+        self.accounts
+            .iter()
+            .any(|(_, status)| matches!(status, MigrationStatus::Failed(_)))
+    }
+}
+
+/// Moves the secrets of `names` (or every account when `names` is empty) into the `to` backend.
+///
+/// Each secret is verified by generating a code before and after the move; accounts that fail
+/// verification are rolled back and reported as [`MigrationStatus::Failed`] without aborting
+/// the rest of the migration.
+pub fn migrate_secrets<P: AsRef<Path>>(
+    config: Config,
+    config_dir: P,
+    to: &SecretLocation,
+    names: &[String],
+) -> TotpResult<(Config, MigrationReport)> {
+    migrate_secrets_with(&secrets::Secrets, config, config_dir.as_ref(), to, names)
+}
+
+fn migrate_secrets_with(
+    backend: &dyn Backend,
+    config: Config,
+    config_dir: &Path,
+    to: &SecretLocation,
+    names: &[String],
+) -> TotpResult<(Config, MigrationReport)> {
+    let mut names: Vec<String> = if names.is_empty() {
+        config.codes().keys().cloned().collect()
+    } else {
+        names.to_vec()
+    };
+    names.sort();
+
+    let mut new_config = config.clone();
+    let mut report = MigrationReport::default();
+    let mut moved: Vec<(String, TotpOptions)> = Vec::new();
+
+    for name in names {
+        let options = match config.lookup(&name) {
+            Ok(options) => options,
+            Err(e) => {
+                report
+                    .accounts
+                    .push((name, MigrationStatus::Failed(e.to_string())));
+                continue;
+            }
+        };
+
+        if options.storage().unwrap_or(&SecretLocation::Config) == to {
+            report
+                .accounts
+                .push((name, MigrationStatus::AlreadyMigrated));
+            continue;
+        }
+
+        match migrate_secret(backend, &config, &name, options, to) {
+            Ok(new_options) => {
+                new_config.insert(name.clone(), new_options);
+                moved.push((name.clone(), options.clone()));
+                report.accounts.push((name, MigrationStatus::Migrated));
+            }
+            Err(e) => report
+                .accounts
+                .push((name, MigrationStatus::Failed(e.to_string()))),
+        }
+    }
+
+    if let Err(e) = config::save_config(config_dir, &new_config) {
+        // Cleanup failures are ignored so that the failure to save is the one reported
+        for (name, _) in &moved {
+            if let Ok(new_options) = new_config.lookup(name) {
+                let _ = backend.remove_secret(name, new_options);
+            }
+        }
+        return Err(e);
+    }
+
+    for (name, old_options) in moved {
+        if let Err(e) = backend.remove_secret(&name, &old_options) {
+            let status = MigrationStatus::Failed(format!(
+                "moved, but the old secret could not be removed: {}",
+                e
+            ));
+            if let Some(entry) = report.accounts.iter_mut().find(|(n, _)| *n == name) {
+                entry.1 = status;
+            }
+        }
+    }
+
+    Ok((new_config, report))
+}
+
+fn migrate_secret(
+    backend: &dyn Backend,
+    config: &Config,
+    name: &str,
+    options: &TotpOptions,
+    to: &SecretLocation,
+) -> TotpResult<TotpOptions> {
+    let now = totp::time_since_epoch();
+    let expected = verification_code(name, &unlocked(backend, name, options)?, now)?;
+
+    let secret = backend.get_secret(name, options)?;
+    let mut new_options = backend.store_secret_at(to, name, &secret, options.algorithm())?;
+    // Failures to clean up the new secret are ignored, so the failure to move it is reported
+    if let Err(e) = passphrase::seal_entry_like(config, name, &mut new_options) {
+        let _ = backend.remove_secret(name, &new_options);
+        return Err(e);
+    }
+    new_options.copy_metadata_from(options);
+    new_options.set_ocra_suite(options.ocra_suite().cloned());
+    let pin = backend.get_pin(name, options).and_then(|pin| {
+        pin.map_or(Ok(()), |pin| {
+            backend.store_pin(name, &mut new_options, &pin)
+        })
+    });
+    if let Err(e) = pin {
+        let _ = backend.remove_secret(name, &new_options);
+        return Err(e);
+    }
+
+    let code = unlocked(backend, name, &new_options)
+        .and_then(|unlocked| verification_code(name, &unlocked, now));
+    match code {
+        Ok(code) if code == expected => Ok(new_options),
+        result => {
+            let _ = backend.remove_secret(name, &new_options);
+            result?;
+            Err(Box::new(TotpConfigError(format!(
+                "the secret stored in {} generates a different code",
+                to
+            ))))
+        }
+    }
+}
+
+/// A config-stored copy of an entry with the secret and PIN its backend holds, to generate codes
+/// from what was actually stored
+fn unlocked(backend: &dyn Backend, name: &str, options: &TotpOptions) -> TotpResult<TotpOptions> {
+    let secret = backend.get_secret(name, options)?;
+    let mut unlocked = TotpOptions::new_config_stored_secret(secret, options.algorithm());
+    unlocked.set_ocra_suite(options.ocra_suite().cloned());
+    unlocked.set_pin(backend.get_pin(name, options)?);
+    Ok(unlocked)
+}
+
+/// Generates a code to compare before and after moving a secret; OCRA tokens respond to a fixed
+/// challenge as they don't generate codes on their own
+fn verification_code(
+    name: &str,
+    options: &TotpOptions,
+    time_since_epoch: Duration,
+) -> TotpResult<String> {
+    match options.algorithm() {
+        TokenAlgorithm::Ocra => {
+            let input = OcraInput {
+                challenge: "0",
+                counter: Some(0),
+                session: Some(&[]),
+                time_since_epoch: Some(time_since_epoch),
+                ..OcraInput::default()
+            };
+            ocra::stored_response(name, options, &input)
+        }
+        _ => token_at(name, options, time_since_epoch),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "keychain")]
+    use crate::{secret::SecretString, secrets::FakeKeychain};
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            [totp.github]
+            storage = "config"
+            secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"
+
+            [totp.gitlab]
+            secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"
+            "#,
+        )
+        .expect("a valid config")
+    }
+
+    #[test]
+    fn secrets_already_in_place_are_reported_and_left_alone() -> TotpResult<()> {
+        let dir = std::env::temp_dir().join(format!("otpcli-migrate-test-{}", std::process::id()));
+        let result = migrate_secrets(config(), &dir, &SecretLocation::Config, &[]);
+        let _ = std::fs::remove_dir_all(&dir);
+        let (new_config, report) = result?;
+
+        assert_eq!(
+            report.accounts,
+            vec![
+                ("github".to_string(), MigrationStatus::AlreadyMigrated),
+                ("gitlab".to_string(), MigrationStatus::AlreadyMigrated),
+            ]
+        );
+        assert!(!report.has_failures());
+        assert_eq!(new_config.codes().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_names_are_reported_as_failures() -> TotpResult<()> {
+        let dir = std::env::temp_dir().join(format!(
+            "otpcli-migrate-unknown-test-{}",
+            std::process::id()
+        ));
+        let names = ["missing".to_string(), "github".to_string()];
+        let result = migrate_secrets(config(), &dir, &SecretLocation::Config, &names);
+        let _ = std::fs::remove_dir_all(&dir);
+        let (_, report) = result?;
+
+        assert!(report.has_failures());
+        assert_eq!(report.accounts.len(), 2);
+        assert_eq!(
+            report.accounts[0],
+            ("github".to_string(), MigrationStatus::AlreadyMigrated)
+        );
+        let (name, status) = &report.accounts[1];
+        assert_eq!(name, "missing");
+        assert!(matches!(status, MigrationStatus::Failed(_)));
+        assert!(status.to_string().starts_with("failed: "));
+        Ok(())
+    }
+
+    #[cfg(feature = "keychain")]
+    fn config_with_pin() -> Config {
+        let mut config = config();
+        let mut github = config.lookup("github").expect("github").clone();
+        github.set_pin(Some(SecretString::from("1234")));
+        config.insert("github".to_string(), github);
+        config
+    }
+
+    #[cfg(feature = "keychain")]
+    fn migrate_to_keychain(
+        keychain: &FakeKeychain,
+        config: Config,
+        dir: &Path,
+    ) -> TotpResult<(Config, MigrationReport)> {
+        migrate_secrets_with(
+            keychain,
+            config,
+            dir,
+            &SecretLocation::KeyChain,
+            &["github".to_string()],
+        )
+    }
+
+    #[cfg(feature = "keychain")]
+    #[test]
+    fn secrets_and_pins_are_moved_into_the_keychain() -> TotpResult<()> {
+        let dir =
+            std::env::temp_dir().join(format!("otpcli-migrate-move-test-{}", std::process::id()));
+        let keychain = FakeKeychain::default();
+        let result = migrate_to_keychain(&keychain, config_with_pin(), &dir);
+        let saved = config::load_config(&dir);
+        let _ = std::fs::remove_dir_all(&dir);
+        let (new_config, report) = result?;
+
+        assert_eq!(
+            report.accounts,
+            vec![("github".to_string(), MigrationStatus::Migrated)]
+        );
+        for config in [&new_config, &saved?].iter() {
+            let github = config.lookup("github")?;
+            assert_eq!(github.storage(), Some(&SecretLocation::KeyChain));
+            assert!(github.secret().is_none());
+            assert!(github.pin().is_none());
+            assert!(github.has_keychain_pin());
+            assert_eq!(config.lookup("gitlab")?.storage(), None);
+        }
+        assert_eq!(
+            keychain.secrets.borrow().get("github"),
+            Some(&SecretString::from("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"))
+        );
+        assert_eq!(
+            keychain.pins.borrow().get("github"),
+            Some(&SecretString::from("1234"))
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "keychain")]
+    #[test]
+    fn secrets_that_generate_other_codes_are_rolled_back() -> TotpResult<()> {
+        let dir = std::env::temp_dir().join(format!(
+            "otpcli-migrate-mismatch-test-{}",
+            std::process::id()
+        ));
+        let keychain = FakeKeychain {
+            corrupt_secrets: true,
+            ..FakeKeychain::default()
+        };
+        let result = migrate_to_keychain(&keychain, config_with_pin(), &dir);
+        let _ = std::fs::remove_dir_all(&dir);
+        let (new_config, report) = result?;
+
+        assert!(report.has_failures());
+        assert_eq!(
+            report.accounts[0].1.to_string(),
+            "failed: the secret stored in keychain generates a different code"
+        );
+        let github = new_config.lookup("github")?;
+        assert_eq!(github.storage(), Some(&SecretLocation::Config));
+        assert_eq!(github.pin(), Some(&SecretString::from("1234")));
+        assert!(keychain.secrets.borrow().is_empty());
+        assert!(keychain.pins.borrow().is_empty());
+        Ok(())
+    }
+
+    #[cfg(feature = "keychain")]
+    #[test]
+    fn secrets_whose_pin_cannot_be_moved_are_rolled_back() -> TotpResult<()> {
+        let dir =
+            std::env::temp_dir().join(format!("otpcli-migrate-pin-test-{}", std::process::id()));
+        let keychain = FakeKeychain {
+            fail_pins: true,
+            ..FakeKeychain::default()
+        };
+        let result = migrate_to_keychain(&keychain, config_with_pin(), &dir);
+        let _ = std::fs::remove_dir_all(&dir);
+        let (new_config, report) = result?;
+
+        assert!(report.has_failures());
+        let github = new_config.lookup("github")?;
+        assert_eq!(github.storage(), Some(&SecretLocation::Config));
+        assert_eq!(github.pin(), Some(&SecretString::from("1234")));
+        assert!(keychain.secrets.borrow().is_empty());
+        Ok(())
+    }
+
+    #[cfg(feature = "keychain")]
+    #[test]
+    fn moved_secrets_are_removed_when_the_config_cannot_be_saved() -> TotpResult<()> {
+        // A file where the config directory should be makes saving fail
+        let dir =
+            std::env::temp_dir().join(format!("otpcli-migrate-save-test-{}", std::process::id()));
+        std::fs::write(&dir, "")?;
+        let keychain = FakeKeychain::default();
+        let result = migrate_to_keychain(&keychain, config_with_pin(), &dir);
+        let _ = std::fs::remove_file(&dir);
+
+        assert!(result.is_err());
+        assert!(keychain.secrets.borrow().is_empty());
+        assert!(keychain.pins.borrow().is_empty());
+        Ok(())
+    }
+}
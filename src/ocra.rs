@@ -0,0 +1,556 @@
+//! Provides [RFC6287] OCRA challenge-response code generation.
+//!
+//! [RFC6287]: https://tools.ietf.org/html/rfc6287
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::config::TotpOptions;
+use crate::encoding::{self, SecretEncoding};
+use crate::secret::{SecretString, REDACTED};
+use crate::secrets;
+use crate::totp::{truncate, HashAlgorithm};
+use crate::{TotpConfigError, TotpError, TotpResult};
+use byteorder::{BigEndian, ByteOrder};
+
+/// Challenges are always padded to 128 bytes before being hashed
+const CHALLENGE_LENGTH: usize = 128;
+
+/// The format challenges (questions) are given in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChallengeFormat {
+    Numeric,
+    Alphanumeric,
+    Hex,
+}
+
+/// A parsed OCRA suite such as `OCRA-1:HOTP-SHA256-8:QN08-PSHA1`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OcraSuite {
+    suite: String,
+    hash: HashAlgorithm,
+    digits: usize,
+    counter: bool,
+    challenge_format: ChallengeFormat,
+    pin_hash: Option<HashAlgorithm>,
+    session_length: Option<usize>,
+    time_step: Option<Duration>,
+}
+
+/// The inputs of a single OCRA computation; which of them are needed depends on the suite
+#[derive(Clone, Default)]
+pub struct OcraInput<'a> {
+    pub challenge: &'a str,
+    pub counter: Option<u64>,
+    /// The PIN, hashed with the suite's PIN hash function
+    pub pin: Option<&'a str>,
+    pub session: Option<&'a [u8]>,
+    pub time_since_epoch: Option<Duration>,
+}
+
+impl Debug for OcraInput<'_> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("OcraInput")
+            .field("challenge", &self.challenge)
+            .field("counter", &self.counter)
+            .field("pin", &self.pin.map(|_| REDACTED))
+            .field("session", &self.session)
+            .field("time_since_epoch", &self.time_since_epoch)
+            .finish()
+    }
+}
+
+fn invalid_suite(suite: &str) -> TotpConfigError {
+    TotpConfigError(format!("Invalid OCRA suite '{}'", suite))
+}
+
+impl FromStr for OcraSuite {
+    type Err = TotpConfigError;
+
+    fn from_str(suite: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = suite.split(':').collect();
+        let (version, function, data_input) = match parts.as_slice() {
+            [version, function, data_input] => (*version, *function, *data_input),
+            _ => return Err(invalid_suite(suite)),
+        };
+        if version != "OCRA-1" {
+            return Err(TotpConfigError(format!(
+                "Unsupported OCRA version '{}'",
+                version
+            )));
+        }
+
+        let (hash, digits) = match function.split('-').collect::<Vec<_>>().as_slice() {
+            ["HOTP", hash, digits] => (hash.parse()?, digits.parse::<usize>().ok()),
+            _ => return Err(invalid_suite(suite)),
+        };
+        let digits = digits.filter(|d| (4..=10).contains(d)).ok_or_else(|| {
+            TotpConfigError(format!("Unsupported OCRA code length in '{}'", suite))
+        })?;
+
+        let mut inputs = data_input.split('-').peekable();
+        let counter = inputs.next_if_eq(&"C").is_some();
+
+        let question = inputs.next().ok_or_else(|| invalid_suite(suite))?;
+        let challenge_format = match question.get(..2) {
+            Some("QN") => ChallengeFormat::Numeric,
+            Some("QA") => ChallengeFormat::Alphanumeric,
+            Some("QH") => ChallengeFormat::Hex,
+            _ => return Err(invalid_suite(suite)),
+        };
+        question[2..]
+            .parse::<usize>()
+            .ok()
+            .filter(|l| (4..=64).contains(l))
+            .ok_or_else(|| invalid_suite(suite))?;
+
+        let mut pin_hash = None;
+        let mut session_length = None;
+        let mut time_step = None;
+        for input in inputs {
+            let kind = input.get(..1).ok_or_else(|| invalid_suite(suite))?;
+            match (kind, &input[1..]) {
+                ("P", hash) if pin_hash.is_none() => pin_hash = Some(hash.parse()?),
+                ("S", length) if session_length.is_none() => {
+                    session_length = Some(length.parse().map_err(|_| invalid_suite(suite))?)
+                }
+                ("T", step) if time_step.is_none() => {
+                    time_step = Some(parse_time_step(step).ok_or_else(|| invalid_suite(suite))?)
+                }
+                _ => return Err(invalid_suite(suite)),
+            }
+        }
+
+        Ok(OcraSuite {
+            suite: suite.to_string(),
+            hash,
+            digits,
+            counter,
+            challenge_format,
+            pin_hash,
+            session_length,
+            time_step,
+        })
+    }
+}
+
+fn parse_time_step(step: &str) -> Option<Duration> {
+    let split = step.len().checked_sub(1)?;
+    let (count, unit) = (step.get(..split)?, step.get(split..)?);
+    let count: u64 = count.parse().ok()?;
+    let (unit, max) = match unit {
+        "S" => (1, 59),
+        "M" => (60, 59),
+        "H" => (60 * 60, 48),
+        _ => return None,
+    };
+    if count == 0 || count > max {
+        return None;
+    }
+    Some(Duration::from_secs(count * unit))
+}
+
+impl Display for OcraSuite {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", self.suite)
+    }
+}
+
+impl OcraSuite {
+    pub fn uses_counter(&self) -> bool {
+        self.counter
+    }
+
+    pub fn uses_pin(&self) -> bool {
+        self.pin_hash.is_some()
+    }
+
+    pub fn uses_session(&self) -> bool {
+        self.session_length.is_some()
+    }
+
+    pub fn uses_timestamp(&self) -> bool {
+        self.time_step.is_some()
+    }
+
+    /// Computes the OCRA response for `input` using the shared `key`.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use otp::ocra::{OcraInput, OcraSuite};
+    ///
+    /// let suite: OcraSuite = "OCRA-1:HOTP-SHA1-6:QN08".parse().unwrap();
+    /// let input = OcraInput {
+    ///     challenge: "11111111",
+    ///     ..OcraInput::default()
+    /// };
+    /// assert_eq!(suite.respond(b"12345678901234567890", &input).unwrap(), "243178");
+    /// ```
+    pub fn respond(&self, key: &[u8], input: &OcraInput) -> TotpResult<String> {
+        let mut message = self.suite.as_bytes().to_vec();
+        message.push(0);
+
+        if self.counter {
+            let counter = input
+                .counter
+                .ok_or(TotpError("This OCRA suite needs a counter"))?;
+            let mut buf = [0; 8];
+            BigEndian::write_u64(&mut buf, counter);
+            message.extend_from_slice(&buf);
+        }
+
+        message.extend(self.challenge_bytes(input.challenge)?);
+
+        if let Some(pin_hash) = self.pin_hash {
+            let pin = input.pin.ok_or(TotpError("This OCRA suite needs a PIN"))?;
+            message.extend(pin_hash.hash(pin.as_bytes()));
+        }
+
+        if let Some(length) = self.session_length {
+            let session = input
+                .session
+                .ok_or(TotpError("This OCRA suite needs session information"))?;
+            if session.len() > length {
+                return Err(Box::new(TotpError("OCRA session information is too long")));
+            }
+            message.extend(std::iter::repeat_n(0, length - session.len()));
+            message.extend_from_slice(session);
+        }
+
+        if let Some(step) = self.time_step {
+            let time = input
+                .time_since_epoch
+                .ok_or(TotpError("This OCRA suite needs a timestamp"))?;
+            let mut buf = [0; 8];
+            BigEndian::write_u64(&mut buf, time.as_secs() / step.as_secs());
+            message.extend_from_slice(&buf);
+        }
+
+        let signature = self.hash.hmac(key, &message);
+        let code = u64::from(truncate(&signature)) % 10u64.pow(self.digits as u32);
+        Ok(format!("{:0width$}", code, width = self.digits))
+    }
+
+    /// Encodes a challenge as hex digits, left aligned in 128 zero padded bytes
+    fn challenge_bytes(&self, challenge: &str) -> TotpResult<Vec<u8>> {
+        // The suite's challenge length isn't enforced, as mutual challenge-response combines
+        // the client and server challenges into one
+        if challenge.is_empty() {
+            return Err(Box::new(TotpError("OCRA challenge is empty")));
+        }
+
+        let hex = match self.challenge_format {
+            ChallengeFormat::Numeric => decimal_to_hex(challenge)?,
+            ChallengeFormat::Alphanumeric if challenge.is_ascii() => {
+                challenge.bytes().map(|b| format!("{:02X}", b)).collect()
+            }
+            ChallengeFormat::Hex if challenge.chars().all(|c| c.is_ascii_hexdigit()) => {
+                challenge.to_string()
+            }
+            _ => {
+                return Err(Box::new(TotpError(
+                    "OCRA challenge contains invalid characters",
+                )))
+            }
+        };
+
+        if hex.len() > CHALLENGE_LENGTH * 2 {
+            return Err(Box::new(TotpError("OCRA challenge is too long")));
+        }
+
+        let mut bytes = vec![0; CHALLENGE_LENGTH];
+        for (i, digit) in hex.chars().enumerate() {
+            let nibble = digit.to_digit(16).expect("validated hex digit") as u8;
+            bytes[i / 2] |= if i % 2 == 0 { nibble << 4 } else { nibble };
+        }
+        Ok(bytes)
+    }
+}
+
+/// Computes the OCRA response of the entry `name` using its stored suite, secret and PIN.
+///
+/// The stored PIN is used unless `input` provides one.
+pub fn stored_response(name: &str, options: &TotpOptions, input: &OcraInput) -> TotpResult<String> {
+    let suite: OcraSuite = options
+        .ocra_suite()
+        .ok_or_else(|| TotpConfigError(format!("'{}' is not an OCRA token", name)))?
+        .parse()?;
+    let secret = secrets::get_secret(name, options)?;
+    let key = encoding::decode_secret(secret.expose(), SecretEncoding::Base32)?;
+    let pin = secrets::get_pin(name, options)?;
+
+    let input = OcraInput {
+        pin: input.pin.or(pin.as_ref().map(SecretString::expose)),
+        ..input.clone()
+    };
+    suite.respond(key.expose(), &input)
+}
+
+/// Converts a decimal number of arbitrary length to upper-case hex without leading zeros
+fn decimal_to_hex(decimal: &str) -> TotpResult<String> {
+    // Little-endian base 16 digits
+    let mut digits: Vec<u32> = vec![0];
+    for c in decimal.chars() {
+        let mut carry = c
+            .to_digit(10)
+            .ok_or(TotpError("Numeric OCRA challenges may only contain digits"))?;
+        for digit in digits.iter_mut() {
+            let value = *digit * 10 + carry;
+            *digit = value % 16;
+            carry = value / 16;
+        }
+        while carry > 0 {
+            digits.push(carry % 16);
+            carry /= 16;
+        }
+    }
+    while digits.len() > 1 && digits.last() == Some(&0) {
+        digits.pop();
+    }
+    Ok(digits
+        .iter()
+        .rev()
+        .map(|d| std::char::from_digit(*d, 16).expect("hex digit"))
+        .collect::<String>()
+        .to_uppercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vectors from https://tools.ietf.org/html/rfc6287#appendix-C
+    const SEED: &[u8] = b"12345678901234567890";
+    const SEED32: &[u8] = b"12345678901234567890123456789012";
+    const SEED64: &[u8] = b"1234567890123456789012345678901234567890123456789012345678901234";
+    const PIN: &str = "1234";
+    // 0x132d0b6 minutes since the epoch
+    const TIMESTAMP: Duration = Duration::from_secs(0x132d0b6 * 60);
+
+    fn respond(suite: &str, key: &[u8], input: OcraInput) -> TotpResult<String> {
+        suite.parse::<OcraSuite>()?.respond(key, &input)
+    }
+
+    #[test]
+    fn one_way_challenge_response() -> TotpResult<()> {
+        let expected = [
+            "237653", "243178", "653583", "740991", "608993", "388898", "816933", "224598",
+            "750600", "294470",
+        ];
+        for (i, code) in expected.iter().enumerate() {
+            let challenge = i.to_string().repeat(8);
+            let input = OcraInput {
+                challenge: &challenge,
+                ..OcraInput::default()
+            };
+            assert_eq!(&respond("OCRA-1:HOTP-SHA1-6:QN08", SEED, input)?, code);
+        }
+
+        let expected = [
+            "65347737", "86775851", "78192410", "71565254", "10104329", "65983500", "70069104",
+            "91771096", "75011558", "08522129",
+        ];
+        for (counter, code) in expected.iter().enumerate() {
+            let input = OcraInput {
+                challenge: "12345678",
+                counter: Some(counter as u64),
+                pin: Some(PIN),
+                ..OcraInput::default()
+            };
+            assert_eq!(
+                &respond("OCRA-1:HOTP-SHA256-8:C-QN08-PSHA1", SEED32, input)?,
+                code
+            );
+        }
+
+        let expected = ["83238735", "01501458", "17957585", "86776967", "86807031"];
+        for (i, code) in expected.iter().enumerate() {
+            let challenge = i.to_string().repeat(8);
+            let input = OcraInput {
+                challenge: &challenge,
+                pin: Some(PIN),
+                ..OcraInput::default()
+            };
+            assert_eq!(
+                &respond("OCRA-1:HOTP-SHA256-8:QN08-PSHA1", SEED32, input)?,
+                code
+            );
+        }
+
+        let expected = [
+            "07016083", "63947962", "70123924", "25341727", "33203315", "34205738", "44343969",
+            "51946085", "20403879", "31409299",
+        ];
+        for (i, code) in expected.iter().enumerate() {
+            let challenge = i.to_string().repeat(8);
+            let input = OcraInput {
+                challenge: &challenge,
+                counter: Some(i as u64),
+                ..OcraInput::default()
+            };
+            assert_eq!(
+                &respond("OCRA-1:HOTP-SHA512-8:C-QN08", SEED64, input)?,
+                code
+            );
+        }
+
+        let expected = ["95209754", "55907591", "22048402", "24218844", "36209546"];
+        for (i, code) in expected.iter().enumerate() {
+            let challenge = i.to_string().repeat(8);
+            let input = OcraInput {
+                challenge: &challenge,
+                time_since_epoch: Some(TIMESTAMP),
+                ..OcraInput::default()
+            };
+            assert_eq!(
+                &respond("OCRA-1:HOTP-SHA512-8:QN08-T1M", SEED64, input)?,
+                code
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mutual_challenge_response() -> TotpResult<()> {
+        type Vector<'a> = (&'a str, &'a [u8], Option<&'a str>, &'a str, [&'a str; 5]);
+        let vectors: [Vector; 4] = [
+            (
+                "OCRA-1:HOTP-SHA256-8:QA08",
+                SEED32,
+                None,
+                "CLI2222{}SRV1111{}",
+                ["28247970", "01984843", "65387857", "03351211", "83412541"],
+            ),
+            (
+                "OCRA-1:HOTP-SHA256-8:QA08",
+                SEED32,
+                None,
+                "SRV1111{}CLI2222{}",
+                ["15510767", "90175646", "33777207", "95285278", "28934924"],
+            ),
+            (
+                "OCRA-1:HOTP-SHA512-8:QA08",
+                SEED64,
+                None,
+                "CLI2222{}SRV1111{}",
+                ["79496648", "76831980", "12250499", "90856481", "12761449"],
+            ),
+            (
+                "OCRA-1:HOTP-SHA512-8:QA08-PSHA1",
+                SEED64,
+                Some(PIN),
+                "SRV1111{}CLI2222{}",
+                ["18806276", "70020315", "01600026", "18951020", "32528969"],
+            ),
+        ];
+        for (suite, key, pin, challenge, expected) in vectors.iter() {
+            for (i, code) in expected.iter().enumerate() {
+                let challenge = challenge.replace("{}", &i.to_string());
+                let input = OcraInput {
+                    challenge: &challenge,
+                    pin: *pin,
+                    ..OcraInput::default()
+                };
+                assert_eq!(&respond(suite, key, input)?, code);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn plain_signature() -> TotpResult<()> {
+        let expected = ["53095496", "04110475", "31331128", "76028668", "46554205"];
+        for (i, code) in expected.iter().enumerate() {
+            let challenge = format!("SIG1{}000", i);
+            let input = OcraInput {
+                challenge: &challenge,
+                ..OcraInput::default()
+            };
+            assert_eq!(&respond("OCRA-1:HOTP-SHA256-8:QA08", SEED32, input)?, code);
+        }
+
+        let expected = ["77537423", "31970405", "10235557", "95213541", "65360607"];
+        for (i, code) in expected.iter().enumerate() {
+            let challenge = format!("SIG1{}00000", i);
+            let input = OcraInput {
+                challenge: &challenge,
+                time_since_epoch: Some(TIMESTAMP),
+                ..OcraInput::default()
+            };
+            assert_eq!(
+                &respond("OCRA-1:HOTP-SHA512-8:QA10-T1M", SEED64, input)?,
+                code
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn suites_are_parsed() -> TotpResult<()> {
+        let suite: OcraSuite = "OCRA-1:HOTP-SHA256-8:C-QH40-PSHA256-S064-T30S".parse()?;
+        assert!(suite.uses_counter());
+        assert!(suite.uses_pin());
+        assert!(suite.uses_session());
+        assert!(suite.uses_timestamp());
+        assert_eq!(suite.challenge_format, ChallengeFormat::Hex);
+        assert_eq!(suite.session_length, Some(64));
+        assert_eq!(suite.time_step, Some(Duration::from_secs(30)));
+        assert_eq!(
+            suite.to_string(),
+            "OCRA-1:HOTP-SHA256-8:C-QH40-PSHA256-S064-T30S"
+        );
+
+        for invalid in [
+            "OCRA-2:HOTP-SHA1-6:QN08",
+            "OCRA-1:HOTP-MD5-6:QN08",
+            "OCRA-1:HOTP-SHA1-11:QN08",
+            "OCRA-1:HOTP-SHA1-6:QX08",
+            "OCRA-1:HOTP-SHA1-6:QN65",
+            "OCRA-1:HOTP-SHA1-6:C",
+            "OCRA-1:HOTP-SHA1-6:QN08-T0M",
+            "OCRA-1:HOTP-SHA1-6:QN08-X",
+            "OCRA-1:HOTP-SHA1-6:QN08-",
+            "OCRA-1:HOTP-SHA1-6:QN08-Tä",
+            "OCRA-1:HOTP-SHA1-6",
+        ]
+        .iter()
+        {
+            assert!(invalid.parse::<OcraSuite>().is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn missing_or_invalid_inputs_are_rejected() -> TotpResult<()> {
+        let suite: OcraSuite = "OCRA-1:HOTP-SHA256-8:C-QN08-PSHA1".parse()?;
+        let input = OcraInput {
+            challenge: "12345678",
+            pin: Some(PIN),
+            ..OcraInput::default()
+        };
+        assert!(suite.respond(SEED32, &input).is_err());
+
+        let suite: OcraSuite = "OCRA-1:HOTP-SHA1-6:QN08".parse()?;
+        let too_long = "A".repeat(129);
+        for challenge in ["", "1234abcd", too_long.as_str()].iter() {
+            let input = OcraInput {
+                challenge,
+                ..OcraInput::default()
+            };
+            assert!(suite.respond(SEED, &input).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn numeric_challenges_are_converted_to_hex() -> TotpResult<()> {
+        assert_eq!(decimal_to_hex("0")?, "0");
+        assert_eq!(decimal_to_hex("00000255")?, "FF");
+        assert_eq!(decimal_to_hex("12345678")?, "BC614E");
+        assert_eq!(
+            decimal_to_hex("340282366920938463463374607431768211456")?,
+            "100000000000000000000000000000000"
+        );
+        Ok(())
+    }
+}
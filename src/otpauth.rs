@@ -0,0 +1,267 @@
+//! Parses the `otpauth://` and `steam://` URIs authenticator apps use to share secrets.
+use std::time::Duration;
+
+use crate::encoding;
+use crate::totp::{HashAlgorithm, TokenAlgorithm, RFC6238_RECOMMENDED_TIMESTEP, STEAM_CODE_LENGTH};
+use crate::{TotpConfigError, TotpError, TotpResult};
+
+/// The secret and metadata carried by an `otpauth://` or `steam://` URI
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtpAuthUri {
+    pub secret: String,
+    pub algorithm: TokenAlgorithm,
+    /// The hash function codes are generated with, SHA1 unless the URI says otherwise
+    pub hash: HashAlgorithm,
+    pub digits: usize,
+    pub period: Duration,
+    pub issuer: Option<String>,
+    pub account: Option<String>,
+}
+
+/// Whether a secret given on the command line is a URI rather than a bare secret
+pub fn is_uri(secret: &str) -> bool {
+    let secret = secret.trim().to_ascii_lowercase();
+    secret.starts_with("otpauth://") || secret.starts_with("steam://")
+}
+
+impl OtpAuthUri {
+    /// Parses an `otpauth://totp/...` or `steam://SECRET` URI.
+    ///
+    /// `otpauth://` URIs with `issuer=Steam` (or `encoder=steam`) are imported as Steam Guard
+    /// tokens. Other URIs may use any of the parameters [`crate::totp::Totp::to_uri`] writes.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use otp::otpauth::OtpAuthUri;
+    /// use otp::totp::TokenAlgorithm;
+    ///
+    /// let uri = OtpAuthUri::parse("otpauth://totp/Steam:gaben?secret=GEZDGNBV&issuer=Steam").unwrap();
+    /// assert_eq!(uri.algorithm, TokenAlgorithm::Steam);
+    /// assert_eq!(uri.account.as_deref(), Some("gaben"));
+    /// ```
+    pub fn parse(uri: &str) -> TotpResult<Self> {
+        let uri = uri.trim();
+        let (scheme, rest) = uri
+            .split_once("://")
+            .ok_or(TotpError("Secret URI is missing a scheme"))?;
+
+        match scheme.to_ascii_lowercase().as_str() {
+            "steam" => Ok(OtpAuthUri {
+                secret: encoding::clean_base32(rest),
+                algorithm: TokenAlgorithm::Steam,
+                hash: HashAlgorithm::Sha1,
+                digits: STEAM_CODE_LENGTH,
+                period: RFC6238_RECOMMENDED_TIMESTEP,
+                issuer: Some("Steam".to_string()),
+                account: None,
+            }),
+            "otpauth" => parse_otpauth(rest),
+            _ => Err(Box::new(TotpConfigError(format!(
+                "Unsupported secret URI scheme '{}'",
+                scheme
+            )))),
+        }
+    }
+
+    /// Whether the codes use the SHA1, 6 digit, 30 second parameters every authenticator app
+    /// supports, or are Steam Guard codes
+    pub fn has_default_parameters(&self) -> bool {
+        self.algorithm == TokenAlgorithm::Steam
+            || (self.hash == HashAlgorithm::Sha1
+                && self.digits == 6
+                && self.period == RFC6238_RECOMMENDED_TIMESTEP)
+    }
+}
+
+fn parse_otpauth(rest: &str) -> TotpResult<OtpAuthUri> {
+    let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let (kind, label) = path.split_once('/').unwrap_or((path, ""));
+    if !kind.eq_ignore_ascii_case("totp") {
+        return Err(Box::new(TotpConfigError(format!(
+            "Unsupported otpauth token type '{}'",
+            kind
+        ))));
+    }
+
+    let label = percent_decode(label)?;
+    let (mut issuer, account) = match label.split_once(':') {
+        Some((issuer, account)) => (Some(issuer.trim().to_string()), account.trim()),
+        None => (None, label.trim()),
+    };
+    let account = Some(account.to_string()).filter(|a| !a.is_empty());
+
+    let mut secret = None;
+    let mut steam = false;
+    let mut digits = None;
+    let mut hash = HashAlgorithm::Sha1;
+    let mut period = RFC6238_RECOMMENDED_TIMESTEP;
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        // Query strings encode spaces as `+`, so only `%2B` is a literal plus
+        let value = percent_decode(&value.replace('+', " "))?;
+        match key.to_ascii_lowercase().as_str() {
+            "secret" => secret = Some(encoding::clean_base32(&value)),
+            "issuer" => issuer = Some(value),
+            "encoder" => steam = value.eq_ignore_ascii_case("steam"),
+            "digits" => digits = Some(value),
+            "algorithm" => {
+                hash = value.parse().map_err(|_| {
+                    TotpConfigError(format!("Unsupported otpauth algorithm '{}'", value))
+                })?
+            }
+            "period" => {
+                period = match value.parse::<u64>() {
+                    Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+                    _ => {
+                        return Err(Box::new(TotpConfigError(format!(
+                            "Unsupported otpauth period '{}'",
+                            value
+                        ))))
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    let issuer = issuer.filter(|i| !i.is_empty());
+    steam = steam
+        || issuer
+            .as_deref()
+            .is_some_and(|i| i.eq_ignore_ascii_case("steam"));
+
+    // Steam URIs often carry the code length of 5, which only makes sense for Steam codes
+    let digits = match digits {
+        _ if steam => STEAM_CODE_LENGTH,
+        None => 6,
+        Some(digits) => match digits.parse() {
+            Ok(digits) if (6..=8).contains(&digits) => digits,
+            _ => {
+                return Err(Box::new(TotpConfigError(format!(
+                    "Unsupported otpauth digits '{}'",
+                    digits
+                ))))
+            }
+        },
+    };
+
+    Ok(OtpAuthUri {
+        secret: secret.ok_or(TotpError("otpauth URI is missing a secret"))?,
+        algorithm: if steam {
+            TokenAlgorithm::Steam
+        } else {
+            TokenAlgorithm::TotpSha1
+        },
+        hash,
+        digits,
+        period,
+        issuer,
+        account,
+    })
+}
+
+/// Percent-encodes everything but the unreserved URI characters
+pub(crate) fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> TotpResult<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = s
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or(TotpError("URI contains an invalid percent escape"))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steam_issuer_uris_import_as_steam_tokens() -> TotpResult<()> {
+        let uri = OtpAuthUri::parse(
+            "otpauth://totp/Steam%3A%20gaben?secret=gezd-gnbv&issuer=Steam&digits=5",
+        )?;
+        assert_eq!(
+            uri,
+            OtpAuthUri {
+                secret: "GEZDGNBV".to_string(),
+                algorithm: TokenAlgorithm::Steam,
+                hash: HashAlgorithm::Sha1,
+                digits: 5,
+                period: Duration::from_secs(30),
+                issuer: Some("Steam".to_string()),
+                account: Some("gaben".to_string()),
+            }
+        );
+
+        let uri = OtpAuthUri::parse("otpauth://totp/gaben?secret=GEZDGNBV&encoder=steam")?;
+        assert_eq!(uri.algorithm, TokenAlgorithm::Steam);
+        Ok(())
+    }
+
+    #[test]
+    fn steam_scheme_uris_import_as_steam_tokens() -> TotpResult<()> {
+        let uri = OtpAuthUri::parse("steam://GEZDGNBV")?;
+        assert_eq!(uri.secret, "GEZDGNBV");
+        assert_eq!(uri.algorithm, TokenAlgorithm::Steam);
+        assert_eq!(uri.issuer.as_deref(), Some("Steam"));
+        Ok(())
+    }
+
+    #[test]
+    fn standard_uris_import_as_sha1_totp() -> TotpResult<()> {
+        let uri = OtpAuthUri::parse(
+            "otpauth://totp/Example:alice@example.com?secret=GEZDGNBV&issuer=Example+Co&algorithm=SHA1&period=30",
+        )?;
+        assert_eq!(uri.algorithm, TokenAlgorithm::TotpSha1);
+        assert_eq!(uri.issuer.as_deref(), Some("Example Co"));
+        assert_eq!(uri.account.as_deref(), Some("alice@example.com"));
+        assert!(uri.has_default_parameters());
+
+        let uri = OtpAuthUri::parse("otpauth://totp/a?secret=GEZDGNBV&issuer=A%2BB")?;
+        assert_eq!(uri.issuer.as_deref(), Some("A+B"));
+        Ok(())
+    }
+
+    #[test]
+    fn uri_parameters_are_parsed() -> TotpResult<()> {
+        let uri = OtpAuthUri::parse(
+            "otpauth://totp/a?secret=GEZDGNBV&algorithm=sha512&digits=8&period=60",
+        )?;
+        assert_eq!(uri.hash, HashAlgorithm::Sha512);
+        assert_eq!(uri.digits, 8);
+        assert_eq!(uri.period, Duration::from_secs(60));
+        assert!(!uri.has_default_parameters());
+        Ok(())
+    }
+
+    #[test]
+    fn unsupported_uris_are_rejected() {
+        assert!(OtpAuthUri::parse("otpauth://hotp/a?secret=GEZDGNBV&counter=1").is_err());
+        assert!(OtpAuthUri::parse("otpauth://totp/a?secret=GEZDGNBV&algorithm=MD5").is_err());
+        assert!(OtpAuthUri::parse("otpauth://totp/a?secret=GEZDGNBV&digits=9").is_err());
+        assert!(OtpAuthUri::parse("otpauth://totp/a?secret=GEZDGNBV&period=0").is_err());
+        assert!(OtpAuthUri::parse("otpauth://totp/a?issuer=Steam").is_err());
+        assert!(OtpAuthUri::parse("otpauth://totp/a%zz?secret=GEZDGNBV").is_err());
+        assert!(OtpAuthUri::parse("https://example.com").is_err());
+    }
+}
@@ -0,0 +1,204 @@
+//! Prints the codes of several secrets at once, as a table, TSV or JSON.
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::str::FromStr;
+use std::time::Duration;
+
+use otp::config::Config;
+use otp::TotpResult;
+use serde::Serialize;
+
+/// How a batch of codes is printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchFormat {
+    /// Aligned columns with a header, for people
+    Table,
+    /// Tab separated name, issuer, code, remaining seconds and error columns, for scripts
+    Tsv,
+    /// An array with an object per secret
+    Json,
+}
+
+impl FromStr for BatchFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "table" => Ok(BatchFormat::Table),
+            "tsv" => Ok(BatchFormat::Tsv),
+            "json" => Ok(BatchFormat::Json),
+            _ => Err(format!(
+                "Unknown output format '{}', expected one of table, tsv or json",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for BatchFormat {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            BatchFormat::Table => write!(f, "table"),
+            BatchFormat::Tsv => write!(f, "tsv"),
+            BatchFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// The code of one secret, or why it couldn't be generated
+#[derive(Debug, Serialize)]
+pub struct Row {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// Seconds until the code changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Pairs the results of [`otp::tokens_at_time`] with the details of their secrets
+pub fn rows(
+    config: &Config,
+    results: Vec<(String, TotpResult<String>)>,
+    now: Duration,
+) -> Vec<Row> {
+    results
+        .into_iter()
+        .map(|(name, code)| {
+            let options = config.lookup(&name).ok();
+            let period = options.and_then(|options| options.algorithm().period());
+            let (code, error) = match code {
+                Ok(code) => (Some(code), None),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            Row {
+                issuer: options.and_then(|options| options.issuer().cloned()),
+                remaining: period
+                    .filter(|_| code.is_some())
+                    .map(|period| period.as_secs() - now.as_secs() % period.as_secs()),
+                name,
+                code,
+                error,
+            }
+        })
+        .collect()
+}
+
+/// Renders the rows, ending with a newline
+pub fn render(rows: &[Row], format: BatchFormat) -> TotpResult<String> {
+    match format {
+        BatchFormat::Table => Ok(table(rows)),
+        BatchFormat::Tsv => Ok(rows
+            .iter()
+            .map(|row| {
+                let columns = [
+                    row.name.clone(),
+                    row.issuer.clone().unwrap_or_default(),
+                    row.code.clone().unwrap_or_default(),
+                    row.remaining.map(|r| r.to_string()).unwrap_or_default(),
+                    row.error.clone().unwrap_or_default(),
+                ];
+                // Tabs and newlines would break the columns
+                let columns: Vec<String> = columns
+                    .iter()
+                    .map(|column| column.replace(&['\t', '\n'][..], " "))
+                    .collect();
+                columns.join("\t") + "\n"
+            })
+            .collect()),
+        BatchFormat::Json => Ok(serde_json::to_string_pretty(rows)? + "\n"),
+    }
+}
+
+fn table(rows: &[Row]) -> String {
+    let header = ["NAME", "ISSUER", "CODE", "REMAINING"];
+    let lines: Vec<[String; 4]> = rows
+        .iter()
+        .map(|row| {
+            [
+                row.name.clone(),
+                row.issuer.clone().unwrap_or_default(),
+                match (&row.code, &row.error) {
+                    (Some(code), _) => code.clone(),
+                    (None, Some(error)) => format!("error: {}", error),
+                    (None, None) => String::new(),
+                },
+                row.remaining
+                    .map(|remaining| format!("{}s", remaining))
+                    .unwrap_or_default(),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.map(str::len);
+    for line in &lines {
+        for (width, column) in widths.iter_mut().zip(line.iter()) {
+            *width = (*width).max(column.chars().count());
+        }
+    }
+
+    std::iter::once(header.map(str::to_string))
+        .chain(lines)
+        .map(|line| {
+            let columns: Vec<String> = line
+                .iter()
+                .zip(widths.iter())
+                .map(|(column, width)| format!("{:width$}", column, width = width))
+                .collect();
+            columns.join("  ").trim_end().to_string() + "\n"
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<Row> {
+        vec![
+            Row {
+                name: "github".to_string(),
+                issuer: Some("GitHub".to_string()),
+                code: Some("123456".to_string()),
+                remaining: Some(12),
+                error: None,
+            },
+            Row {
+                name: "missing".to_string(),
+                issuer: None,
+                code: None,
+                remaining: None,
+                error: Some("not found".to_string()),
+            },
+        ]
+    }
+
+    #[test]
+    fn tables_are_aligned_and_show_failures() -> TotpResult<()> {
+        assert_eq!(
+            render(&sample(), BatchFormat::Table)?,
+            "NAME     ISSUER  CODE              REMAINING\n\
+             github   GitHub  123456            12s\n\
+             missing          error: not found\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn tsv_and_json_keep_every_field() -> TotpResult<()> {
+        assert_eq!(
+            render(&sample(), BatchFormat::Tsv)?,
+            "github\tGitHub\t123456\t12\t\nmissing\t\t\t\tnot found\n"
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&render(&sample(), BatchFormat::Json)?)?;
+        assert_eq!(json[0]["code"], "123456");
+        assert_eq!(json[0]["remaining"], 12);
+        assert_eq!(json[1]["error"], "not found");
+        assert!(json[1].get("code").is_none());
+        Ok(())
+    }
+}
@@ -1,10 +1,22 @@
-#[cfg(feature = "ras_stoken")]
+use otp::{
+    config::{MetadataUpdate, PinPosition, SecretLocation},
+    encoding::SecretEncoding,
+    totp::TokenAlgorithm,
+    TotpError, TotpResult,
+};
 use std::path::PathBuf;
-
-use otp::{TotpError, TotpResult};
+use structopt::clap::Shell;
 use structopt::StructOpt;
 
+use crate::batch::BatchFormat;
+#[cfg(feature = "copy")]
+use crate::copy::ClipboardBackend;
+use crate::format::Template;
+use crate::pick::Launcher;
+use crate::typing::TypingBackend;
+
 #[derive(StructOpt)]
+#[structopt(about = env!("CARGO_PKG_DESCRIPTION"))]
 #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
 pub struct Options {
     #[structopt(subcommand)]
@@ -14,6 +26,7 @@ pub struct Options {
     #[structopt(name = "name")]
     pub name: Option<String>,
 
+    /// Shows more details when listing secrets
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     pub verbosity: u8,
 
@@ -25,6 +38,39 @@ pub struct Options {
     #[cfg(feature = "copy")]
     #[structopt(long = "copy")]
     pub copy_to_clipboard: bool,
+
+    /// The clipboard to copy to: native, wayland, osc52 or tmux (detected from the environment by
+    /// default)
+    #[cfg(feature = "copy")]
+    #[structopt(long = "clipboard-backend")]
+    pub clipboard_backend: Option<ClipboardBackend>,
+
+    /// How to print the token, e.g. '{name}: {code} ({remaining}s)', with {name}, {issuer},
+    /// {code}, {next_code}, {remaining}, {period} and {algorithm} placeholders
+    #[structopt(long = "format")]
+    pub format: Option<Template>,
+
+    /// Prints the digits of the token in groups of this size, e.g. 3 for '123 456'
+    #[structopt(long = "group-digits")]
+    pub group_digits: Option<usize>,
+
+    /// Prints, copies or types the token combined with its stored PIN or password, for logins
+    /// that expect both in one field
+    #[structopt(long = "with-pin")]
+    pub with_pin: bool,
+
+    /// Types the generated token into the focused window
+    #[structopt(long = "keystrokes")]
+    pub type_code: bool,
+
+    /// How to type the token: xdotool, wtype or uinput (detected from the environment by
+    /// default)
+    #[structopt(long = "typing-backend")]
+    pub typing_backend: Option<TypingBackend>,
+
+    /// Milliseconds to wait between keystrokes when typing the token
+    #[structopt(long = "type-delay", default_value = "12")]
+    pub type_delay: u64,
 }
 
 impl Options {
@@ -32,6 +78,52 @@ impl Options {
     pub fn copy_to_clipboard(&self) -> bool {
         self.copy_to_clipboard
     }
+
+    #[cfg(feature = "copy")]
+    pub fn clipboard_backend(&self) -> ClipboardBackend {
+        self.clipboard_backend
+            .unwrap_or_else(ClipboardBackend::detect)
+    }
+
+    pub fn typing_backend(&self) -> TypingBackend {
+        self.typing_backend.unwrap_or_else(TypingBackend::detect)
+    }
+}
+
+#[derive(StructOpt, Clone)]
+pub struct MetadataArgs {
+    /// The service or organization that issued the secret
+    #[structopt(long = "issuer")]
+    pub issuer: Option<String>,
+
+    /// The account or user name the secret belongs to
+    #[structopt(long = "account")]
+    pub account: Option<String>,
+
+    /// Adds a tag to the entry (can be repeated)
+    #[structopt(long = "tag", number_of_values = 1)]
+    pub tags: Vec<String>,
+
+    /// Free-form notes about the entry
+    #[structopt(long = "notes")]
+    pub notes: Option<String>,
+
+    /// Whether --with-pin puts the PIN before or after the code: prefix or suffix
+    #[structopt(long = "pin-position")]
+    pub pin_position: Option<PinPosition>,
+}
+
+impl MetadataArgs {
+    pub fn into_update(self, remove_tags: Vec<String>) -> MetadataUpdate {
+        MetadataUpdate {
+            issuer: self.issuer,
+            account: self.account,
+            notes: self.notes,
+            add_tags: self.tags,
+            remove_tags,
+            pin_position: self.pin_position,
+        }
+    }
 }
 
 #[derive(StructOpt, Clone)]
@@ -39,34 +131,230 @@ pub enum Command {
     /// Add/Update a new TOTP secret
     #[structopt(name = "add")]
     #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
-    AddSecret { name: String, secret: String },
+    AddSecret {
+        name: String,
+        /// The secret, or an otpauth:// or steam:// URI
+        secret: String,
+        /// The encoding of the secret: base32, hex or ascii
+        #[structopt(long = "encoding", default_value = "base32")]
+        encoding: SecretEncoding,
+        /// The token type: sha1, steam, motp, yandex or ocra (mOTP secrets are used as text)
+        #[structopt(long = "type")]
+        algorithm: Option<TokenAlgorithm>,
+        /// The PIN of mOTP, Yandex Key and OCRA tokens, or a PIN or password to combine with the
+        /// codes of other tokens with --with-pin
+        #[structopt(long = "pin")]
+        pin: Option<String>,
+        /// The OCRA suite of a challenge-response token, e.g. OCRA-1:HOTP-SHA1-6:QN08
+        #[structopt(long = "suite")]
+        suite: Option<String>,
+        #[structopt(flatten)]
+        metadata: MetadataArgs,
+    },
+    /// Edit the issuer, account, tags, notes or PIN of a TOTP secret
+    #[structopt(name = "edit")]
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    EditSecret {
+        name: String,
+        #[structopt(flatten)]
+        metadata: MetadataArgs,
+        /// Stores a new PIN or password, kept in the same place as the secret. Prompted for when
+        /// no value is given, which keeps it out of the shell's history and the process list
+        #[structopt(long = "pin")]
+        pin: Option<Option<String>>,
+        /// Read the new PIN or password from the first line of this file
+        #[structopt(long = "pin-file", parse(from_os_str), conflicts_with = "pin")]
+        pin_file: Option<PathBuf>,
+        /// Removes a tag from the entry (can be repeated)
+        #[structopt(long = "untag", number_of_values = 1)]
+        untag: Vec<String>,
+    },
     /// Import an RSAToken into otpcli
-    #[cfg(feature = "ras_stoken")]
+    #[cfg(feature = "rsa_stoken")]
     #[structopt(name = "import")]
     #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
     ImportStoken {
         name: String,
-        #[structopt(parse(from_os_str))]
-        path: PathBuf,
-        pin: String,
+        /// Path to an .sdtid file, or a CTF token string or com.rsa.securid:// URI
+        token: String,
+        /// The token PIN, if the token is used with one
+        pin: Option<String>,
+        /// The password a CTF token was protected with
+        #[structopt(long = "password")]
+        password: Option<String>,
+        /// The device ID a CTF token was issued for
+        #[structopt(long = "device-id")]
+        device_id: Option<String>,
+        /// Overrides the tokencode length (6 or 8 digits)
+        #[structopt(long = "digits")]
+        digits: Option<usize>,
     },
-    /// Add/Update a new TOTP secret
+    /// List the TOTP secrets
     #[structopt(name = "list")]
     #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
-    ListSecrets { prefix: Option<String> },
-    /// Add/Update a new TOTP secret
+    ListSecrets {
+        prefix: Option<String>,
+        /// Only list entries with this tag
+        #[structopt(long = "tag")]
+        tag: Option<String>,
+        /// Only list entries from this issuer
+        #[structopt(long = "issuer")]
+        issuer: Option<String>,
+    },
+    /// Rename a TOTP secret, keeping its stored secret
+    #[structopt(name = "rename")]
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    RenameSecret { old: String, new: String },
+    /// Copy a TOTP secret to a new name
+    #[structopt(name = "cp")]
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    CopySecret { src: String, dst: String },
+    /// Delete a TOTP secret
     #[structopt(name = "delete")]
     #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
-    DeleteSecret { name: String },
+    DeleteSecret {
+        name: String,
+        /// Delete without asking for confirmation
+        #[structopt(short = "y", long = "yes")]
+        yes: bool,
+    },
+    /// Remove keychain secrets that no longer belong to any TOTP config
+    #[cfg(feature = "keychain")]
+    #[structopt(name = "gc")]
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    CollectGarbage {
+        /// Only list orphaned secrets, without removing them
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+        /// Remove without asking for confirmation
+        #[structopt(short = "y", long = "yes")]
+        yes: bool,
+    },
+    /// Move secrets to a different storage backend (config or keychain)
+    #[structopt(name = "migrate")]
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    MigrateSecrets {
+        /// Where to store the secrets
+        #[structopt(long = "to")]
+        to: SecretLocation,
+        /// The names of the secrets to migrate (defaults to all of them)
+        names: Vec<String>,
+    },
     /// Migrate secrets stored in the config to be stored in the keychain
     #[cfg(feature = "keychain")]
     #[structopt(name = "migrate-to-keychain")]
     #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
     UseKeychain,
-    /// Generate a token
+    /// Respond to an OCRA challenge
+    #[structopt(name = "ocra")]
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    OcraResponse {
+        name: String,
+        /// The challenge (question) to respond to
+        #[structopt(long = "challenge")]
+        challenge: String,
+        /// The counter value, for suites that use one
+        #[structopt(long = "counter")]
+        counter: Option<u64>,
+        /// Hex encoded session information, for suites that use it
+        #[structopt(long = "session")]
+        session: Option<String>,
+    },
+    /// Run an agent that keeps secrets unlocked and generates codes for other invocations
+    #[cfg(unix)]
+    #[structopt(name = "agent")]
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Agent {
+        /// The socket to listen on, in a directory only you can access (defaults to one in
+        /// $XDG_RUNTIME_DIR)
+        #[structopt(long = "socket", parse(from_os_str))]
+        socket: Option<PathBuf>,
+        /// Seconds without any requests after which the agent exits
+        #[structopt(long = "idle-timeout", default_value = "900")]
+        idle_timeout: u64,
+        /// Keep running in the foreground instead of forking into the background
+        #[structopt(short = "D", long = "foreground")]
+        foreground: bool,
+    },
+    /// Log in to OpenVPN with a generated token, through a temporary auth file or its management
+    /// interface
+    #[cfg(unix)]
+    #[structopt(name = "openvpn")]
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    OpenVpn {
+        name: String,
+        /// The VPN user name (defaults to the entry's account)
+        #[structopt(long = "username")]
+        username: Option<String>,
+        /// Answers the management interface at this unix socket or host:port instead of writing
+        /// an auth file
+        #[structopt(long = "management")]
+        management: Option<String>,
+        /// Read the management interface's password from the first line of this file
+        #[structopt(long = "management-password-file", parse(from_os_str))]
+        management_password_file: Option<PathBuf>,
+        /// Seconds after which the auth file is removed if the command is still running
+        #[structopt(long = "remove-after", default_value = "30")]
+        remove_after: u64,
+        /// The command to run with the auth file, e.g. `-- openvpn --config vpn.ovpn`, whose path
+        /// replaces a `{}` argument or is passed with --auth-user-pass
+        #[structopt(last = true, required_unless = "management")]
+        command: Vec<String>,
+    },
+    /// Encrypt the secrets stored in the config with a new master passphrase
+    #[structopt(name = "passwd")]
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    ChangePassphrase {
+        /// Read the new passphrase from the first line of this file instead of prompting for it
+        #[structopt(long = "new-passphrase-file", parse(from_os_str))]
+        new_passphrase_file: Option<PathBuf>,
+        /// Store the secrets unencrypted again
+        #[structopt(long = "remove", conflicts_with = "new-passphrase-file")]
+        remove: bool,
+    },
+    /// Pick an entry with dmenu, rofi, fzf or another launcher, and generate its token
+    #[structopt(name = "pick")]
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Pick {
+        /// dmenu, rofi, fzf, or a command that reads entries from stdin and prints the one
+        /// picked
+        #[structopt(long = "launcher", default_value = "dmenu")]
+        launcher: Launcher,
+    },
+    /// Print a script that sets up tab completion for a shell
+    #[structopt(name = "completions")]
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Completions {
+        /// The shell to complete in: bash, zsh, fish, elvish or powershell
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+    /// Print the man page, or write the pages of every command to a directory
+    #[structopt(name = "man")]
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Man {
+        /// Write otpcli.1 and a page for each subcommand into this directory
+        #[structopt(long = "out-dir", parse(from_os_str))]
+        out_dir: Option<PathBuf>,
+    },
+    /// Generate a token, or the tokens of several secrets at once
     #[structopt(name = "generate")]
     #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
-    GenerateToken { name: String },
+    GenerateToken {
+        /// The names of the TOTP secrets to generate tokens for
+        #[structopt(required_unless_one = &["all", "tag"])]
+        names: Vec<String>,
+        /// Generate the tokens of every secret
+        #[structopt(long = "all", conflicts_with_all = &["names", "tag"])]
+        all: bool,
+        /// Generate the tokens of the secrets with this tag
+        #[structopt(long = "tag", conflicts_with = "names")]
+        tag: Option<String>,
+        /// How to print several tokens: table, tsv or json (a table by default, or just the
+        /// token for a single name)
+        #[structopt(long = "output")]
+        output: Option<BatchFormat>,
+    },
 }
 
 impl Options {
@@ -80,7 +368,92 @@ impl Options {
         }
 
         Ok(self.cmd.clone().unwrap_or_else(|| Command::GenerateToken {
-            name: self.name.clone().unwrap(),
+            names: vec![self.name.clone().unwrap()],
+            all: false,
+            tag: None,
+            output: None,
         }))
     }
 }
+
+impl Command {
+    /// The name of the one token to print on its own, with `--format`, `--copy` and the other
+    /// single token options, rather than as a batch
+    pub fn single_token(&self) -> Option<&String> {
+        match self {
+            Command::GenerateToken {
+                names,
+                all: false,
+                tag: None,
+                output: None,
+            } if names.len() == 1 => names.first(),
+            _ => None,
+        }
+    }
+}
+
+/// Commands otpcli runs for itself, kept apart from [`Options`] so they stay out of its help,
+/// completions and man pages
+#[derive(StructOpt)]
+#[structopt(name = "otpcli")]
+pub enum Helper {
+    /// Print the names of the TOTP secrets, for shell completion
+    #[structopt(name = "__complete-names")]
+    CompleteNames,
+    /// Holds a code on the clipboard, then clears it
+    #[cfg(feature = "copy")]
+    #[structopt(name = "__clipboard")]
+    HoldClipboard {
+        #[structopt(long = "backend")]
+        backend: ClipboardBackend,
+        #[structopt(long = "clear-after")]
+        clear_after: u64,
+        #[structopt(long = "restore")]
+        restore: bool,
+    },
+}
+
+impl Helper {
+    /// The helper command otpcli was run as, if it was run as one
+    pub fn from_args_if_helper() -> Option<Helper> {
+        let command = std::env::args_os().nth(1)?;
+        // Parsing anything else could print clap's version or help a second time
+        if !command.to_string_lossy().starts_with("__") {
+            return None;
+        }
+        Helper::from_iter_safe(std::env::args_os()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> TotpResult<Command> {
+        Options::from_iter_safe(args)?.command()
+    }
+
+    #[test]
+    fn only_plain_single_names_are_single_tokens() -> TotpResult<()> {
+        let single = command(&["otpcli", "github"])?;
+        assert_eq!(single.single_token().map(String::as_str), Some("github"));
+        let single = command(&["otpcli", "generate", "github"])?;
+        assert_eq!(single.single_token().map(String::as_str), Some("github"));
+
+        for (format, expected) in &[("json", BatchFormat::Json), ("tsv", BatchFormat::Tsv)] {
+            let batch = command(&["otpcli", "generate", "github", "--output", format])?;
+            assert!(batch.single_token().is_none());
+            match batch {
+                Command::GenerateToken { names, output, .. } => {
+                    assert_eq!(names, ["github"]);
+                    assert_eq!(output, Some(*expected));
+                }
+                _ => panic!("expected a generate command"),
+            }
+        }
+        assert!(command(&["otpcli", "generate", "--all"])?
+            .single_token()
+            .is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+//! Generates shell completion scripts, which complete TOTP secret names by running the helper
+//! `__complete-names` command.
+use std::io::Write;
+
+use structopt::clap::Shell;
+use structopt::StructOpt;
+
+use crate::cli::Options;
+use otp::TotpResult;
+
+/// The helper command that prints the names of the TOTP secrets
+pub const COMPLETE_NAMES_COMMAND: &str = "__complete-names";
+
+/// The subcommands whose first argument is the name of an existing secret
+const NAME_COMMANDS: &[&str] = &[
+    "generate", "edit", "delete", "rename", "cp", "ocra", "migrate", "openvpn",
+];
+/// The positional arguments that take the name of an existing secret
+const NAME_ARGS: &[&str] = &["name", "names", "old", "src"];
+
+/// Writes the completion script for `shell` to `out`
+pub fn generate<W: Write>(shell: Shell, out: &mut W) -> TotpResult<()> {
+    let mut app = Options::clap();
+
+    let mut script = Vec::new();
+    app.gen_completions_to("otpcli", shell, &mut script);
+    let script = String::from_utf8(script)?;
+
+    let script = match shell {
+        Shell::Bash => bash(script),
+        Shell::Zsh => zsh(&script),
+        Shell::Fish => fish(script),
+        Shell::Elvish => elvish(&script),
+        Shell::PowerShell => powershell(&script),
+    };
+    out.write_all(script.as_bytes())?;
+    Ok(())
+}
+
+/// Wraps clap's completion function, adding names after `otpcli` and the name commands
+fn bash(script: String) -> String {
+    format!(
+        r#"{script}
+_otpcli_names() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    _otpcli "$@"
+    if [[ ${{cur}} != -* ]]; then
+        case "${{prev}}" in
+            otpcli|{commands})
+                COMPREPLY+=( $(compgen -W "$(otpcli {complete_names} 2>/dev/null)" -- "${{cur}}") )
+                ;;
+        esac
+    fi
+}}
+
+complete -F _otpcli_names -o bashdefault -o default otpcli
+"#,
+        script = script,
+        commands = NAME_COMMANDS.join("|"),
+        complete_names = COMPLETE_NAMES_COMMAND,
+    )
+}
+
+/// Completes the name arguments with names instead of files
+fn zsh(script: &str) -> String {
+    let mut lines: Vec<String> = script
+        .lines()
+        .map(|line| {
+            let arg = line.trim_start().trim_start_matches(&['\'', ':'][..]);
+            let is_name_arg = NAME_ARGS.iter().any(|name| {
+                arg.starts_with(&format!("{}:", name)) || arg.starts_with(&format!("{} --", name))
+            });
+            if is_name_arg {
+                line.replace(":_files'", ":_otpcli_names'")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+
+    // The script ends by calling the completion function, which has to come after ours
+    let call = lines.pop().unwrap_or_default();
+    lines.push(format!(
+        r#"_otpcli_names() {{
+    local -a names
+    names=(${{(f)"$(otpcli {} 2>/dev/null)"}})
+    compadd -a names
+}}
+"#,
+        COMPLETE_NAMES_COMMAND
+    ));
+    lines.push(call);
+    lines.join("\n") + "\n"
+}
+
+fn fish(script: String) -> String {
+    format!(
+        r#"{script}complete -c otpcli -n "__fish_use_subcommand" -f -a "(otpcli {complete_names})"
+complete -c otpcli -n "__fish_seen_subcommand_from {commands}" -f -a "(otpcli {complete_names})"
+"#,
+        script = script,
+        commands = NAME_COMMANDS.join(" "),
+        complete_names = COMPLETE_NAMES_COMMAND,
+    )
+}
+
+/// The completion commands, as `otpcli;<subcommand>`, after which names are completed
+fn command_paths(separator: &str) -> String {
+    std::iter::once("'otpcli'".to_string())
+        .chain(
+            NAME_COMMANDS
+                .iter()
+                .map(|command| format!("'otpcli;{}'", command)),
+        )
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+fn elvish(script: &str) -> String {
+    script.replacen(
+        "    $completions[$command]\n",
+        &format!(
+            r#"    $completions[$command]
+    if (has-value [{commands}] $command) {{
+        otpcli {complete_names} | each [name]{{ cand $name 'TOTP secret' }}
+    }}
+"#,
+            commands = command_paths(" "),
+            complete_names = COMPLETE_NAMES_COMMAND,
+        ),
+        1,
+    )
+}
+
+fn powershell(script: &str) -> String {
+    script.replacen(
+        "    $completions.Where",
+        &format!(
+            r#"    if ($command -in {commands}) {{
+        $completions += @(otpcli {complete_names} | ForEach-Object {{
+            [CompletionResult]::new($_, $_, [CompletionResultType]::ParameterValue, 'TOTP secret')
+        }})
+    }}
+
+    $completions.Where"#,
+            commands = command_paths(", "),
+            complete_names = COMPLETE_NAMES_COMMAND,
+        ),
+        1,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts_complete_names_but_not_helper_commands() -> TotpResult<()> {
+        for shell in Shell::variants().iter() {
+            let shell: Shell = shell.parse()?;
+            let mut script = Vec::new();
+            generate(shell, &mut script)?;
+            let script = String::from_utf8(script)?;
+
+            assert!(
+                script.contains(&format!("otpcli {}", COMPLETE_NAMES_COMMAND)),
+                "{} doesn't complete names",
+                shell
+            );
+            assert!(
+                script.contains("keystrokes"),
+                "{} doesn't offer --keystrokes",
+                shell
+            );
+            assert!(
+                !script.contains("__clipboard"),
+                "{} offers __clipboard",
+                shell
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn zsh_completes_name_arguments_with_names() -> TotpResult<()> {
+        let mut script = Vec::new();
+        generate(Shell::Zsh, &mut script)?;
+        let script = String::from_utf8(script)?;
+
+        assert!(script.contains("':name:_otpcli_names'"));
+        assert!(!script.contains("':name:_files'"));
+        assert!(script.trim_end().ends_with("_otpcli \"$@\""));
+        Ok(())
+    }
+}
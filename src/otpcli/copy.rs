@@ -0,0 +1,257 @@
+//! Copies codes to the clipboard, and clears them again once they've had time to be pasted.
+//!
+//! Clearing is done by a detached copy of otpcli (the helper `__clipboard` command), which
+//! reads the code from its stdin, copies it and holds on to it until the timeout passes.
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
+
+use clipboard::{ClipboardContext, ClipboardProvider};
+
+use crate::base64;
+use otp::config::ClipboardConfig;
+use otp::{TotpError, TotpResult};
+
+/// The command the clipboard helper runs as
+pub const HELPER_COMMAND: &str = "__clipboard";
+
+/// Where copied codes go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipboardBackend {
+    /// The system clipboard through the `clipboard` crate: X11's on Linux, and the native one on
+    /// macOS and Windows
+    Native,
+    /// The Wayland clipboard, through `wl-copy` and `wl-paste`
+    Wayland,
+    /// The terminal's clipboard, set with an OSC 52 escape sequence, which also works over SSH
+    Osc52,
+    /// The tmux paste buffer
+    Tmux,
+}
+
+impl FromStr for ClipboardBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "native" | "x11" => Ok(ClipboardBackend::Native),
+            "wayland" => Ok(ClipboardBackend::Wayland),
+            "osc52" => Ok(ClipboardBackend::Osc52),
+            "tmux" => Ok(ClipboardBackend::Tmux),
+            _ => Err(format!(
+                "Unknown clipboard backend '{}', expected one of native, wayland, osc52 or tmux",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for ClipboardBackend {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            ClipboardBackend::Native => write!(f, "native"),
+            ClipboardBackend::Wayland => write!(f, "wayland"),
+            ClipboardBackend::Osc52 => write!(f, "osc52"),
+            ClipboardBackend::Tmux => write!(f, "tmux"),
+        }
+    }
+}
+
+impl ClipboardBackend {
+    /// Picks the backend for the current session: the display server's clipboard, then tmux,
+    /// then the terminal's. macOS and Windows always have their native clipboard.
+    pub fn detect() -> Self {
+        if cfg!(not(target_os = "linux")) {
+            return ClipboardBackend::Native;
+        }
+        let is_set = |var: &str| std::env::var_os(var).is_some_and(|value| !value.is_empty());
+        if is_set("WAYLAND_DISPLAY") {
+            ClipboardBackend::Wayland
+        } else if is_set("DISPLAY") {
+            ClipboardBackend::Native
+        } else if is_set("TMUX") {
+            ClipboardBackend::Tmux
+        } else {
+            ClipboardBackend::Osc52
+        }
+    }
+
+    fn set_contents(self, contents: &str) -> TotpResult<()> {
+        match self {
+            ClipboardBackend::Native => {
+                let mut clipboard: ClipboardContext = ClipboardProvider::new()?;
+                clipboard.set_contents(contents.to_string())?;
+                Ok(())
+            }
+            ClipboardBackend::Wayland => run("wl-copy", &[], Some(contents)).map(|_| ()),
+            ClipboardBackend::Osc52 => {
+                let mut tty = std::fs::OpenOptions::new().write(true).open("/dev/tty")?;
+                tty.write_all(osc52(contents).as_bytes())?;
+                Ok(())
+            }
+            ClipboardBackend::Tmux => {
+                run("tmux", &["load-buffer", "-"], Some(contents)).map(|_| ())
+            }
+        }
+    }
+
+    /// The clipboard's contents, or `None` for backends that can't be read
+    fn get_contents(self) -> TotpResult<Option<String>> {
+        match self {
+            ClipboardBackend::Native => {
+                let mut clipboard: ClipboardContext = ClipboardProvider::new()?;
+                Ok(Some(clipboard.get_contents()?))
+            }
+            ClipboardBackend::Wayland => run("wl-paste", &["--no-newline"], None).map(Some),
+            ClipboardBackend::Osc52 => Ok(None),
+            ClipboardBackend::Tmux => run("tmux", &["save-buffer", "-"], None).map(Some),
+        }
+    }
+
+    fn clear(self) -> TotpResult<()> {
+        match self {
+            ClipboardBackend::Wayland => run("wl-copy", &["--clear"], None).map(|_| ()),
+            ClipboardBackend::Tmux => run("tmux", &["delete-buffer"], None).map(|_| ()),
+            ClipboardBackend::Native | ClipboardBackend::Osc52 => self.set_contents(""),
+        }
+    }
+}
+
+/// Runs a clipboard tool, returning its output when no input is given
+fn run(program: &str, args: &[&str], input: Option<&str>) -> TotpResult<String> {
+    let mut command = Command::new(program);
+    command.args(args).stderr(Stdio::null());
+    // wl-copy forks to serve the clipboard, and would keep a piped stdout open
+    match input {
+        Some(_) => command.stdin(Stdio::piped()).stdout(Stdio::null()),
+        None => command.stdin(Stdio::null()).stdout(Stdio::piped()),
+    };
+
+    let mut child = command.spawn()?;
+    if let Some(input) = input {
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        stdin.write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(Box::new(TotpError::of("The clipboard tool failed")));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// The OSC 52 escape sequence that sets the terminal's clipboard to `contents`
+fn osc52(contents: &str) -> String {
+    format!("\x1b]52;c;{}\x07", base64::encode(contents.as_bytes()))
+}
+
+/// Copies `code` to the clipboard, clearing it again as configured.
+///
+/// The terminal's clipboard can't be read back to check it still holds the code, so codes
+/// copied with OSC 52 are never cleared.
+pub fn copy_code(
+    code: &str,
+    backend: ClipboardBackend,
+    settings: &ClipboardConfig,
+) -> TotpResult<()> {
+    match (settings.clear_after(), backend) {
+        (Some(clear_after), ClipboardBackend::Native)
+        | (Some(clear_after), ClipboardBackend::Wayland)
+        | (Some(clear_after), ClipboardBackend::Tmux) => {
+            spawn_helper(code, backend, clear_after, settings.restore())
+        }
+        (Some(_), ClipboardBackend::Osc52) | (None, _) => backend.set_contents(code),
+    }
+}
+
+fn spawn_helper(
+    code: &str,
+    backend: ClipboardBackend,
+    clear_after: Duration,
+    restore: bool,
+) -> TotpResult<()> {
+    let mut helper = Command::new(std::env::current_exe()?);
+    helper
+        .arg(HELPER_COMMAND)
+        .arg("--backend")
+        .arg(backend.to_string())
+        .arg("--clear-after")
+        .arg(clear_after.as_secs().to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    if restore {
+        helper.arg("--restore");
+    }
+    // Keep the helper out of the terminal's process group, so Ctrl-C in the shell doesn't
+    // stop it from clearing the clipboard
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut helper, 0);
+
+    let mut child = helper.spawn()?;
+    // The code is passed on stdin to keep it out of the process list; closing it lets the
+    // helper carry on without us
+    child
+        .stdin
+        .take()
+        .expect("helper stdin is piped")
+        .write_all(code.as_bytes())?;
+    Ok(())
+}
+
+/// Runs the clipboard helper: copies the code read from stdin, then clears it (or restores
+/// what was there before) after `clear_after`, unless something else has been copied since
+pub fn hold_code(
+    backend: ClipboardBackend,
+    clear_after: Duration,
+    restore: bool,
+) -> TotpResult<()> {
+    let mut code = String::new();
+    std::io::stdin().read_to_string(&mut code)?;
+
+    let previous = if restore {
+        backend.get_contents().ok().flatten()
+    } else {
+        None
+    };
+    backend.set_contents(&code)?;
+
+    std::thread::sleep(clear_after);
+    if backend.get_contents()?.as_deref() == Some(code.as_str()) {
+        match previous {
+            Some(previous) if !previous.is_empty() => backend.set_contents(&previous)?,
+            _ => backend.clear()?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn osc52_sequences_are_base64_encoded() {
+        assert_eq!(osc52("123456"), "\x1b]52;c;MTIzNDU2\x07");
+    }
+
+    #[test]
+    fn backends_parse_from_their_names() {
+        for backend in [
+            ClipboardBackend::Native,
+            ClipboardBackend::Wayland,
+            ClipboardBackend::Osc52,
+            ClipboardBackend::Tmux,
+        ]
+        .iter()
+        {
+            assert_eq!(
+                backend.to_string().parse::<ClipboardBackend>(),
+                Ok(*backend)
+            );
+        }
+        assert_eq!("x11".parse(), Ok(ClipboardBackend::Native));
+        assert!("pbcopy".parse::<ClipboardBackend>().is_err());
+    }
+}
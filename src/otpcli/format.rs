@@ -0,0 +1,201 @@
+//! Formats generated codes with templates like `{name}: {code} ({remaining}s)`.
+use std::str::FromStr;
+
+/// The values a template can refer to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    Name,
+    Issuer,
+    Code,
+    NextCode,
+    Remaining,
+    Period,
+    Algorithm,
+}
+
+impl FromStr for Placeholder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(Placeholder::Name),
+            "issuer" => Ok(Placeholder::Issuer),
+            "code" => Ok(Placeholder::Code),
+            "next_code" => Ok(Placeholder::NextCode),
+            "remaining" => Ok(Placeholder::Remaining),
+            "period" => Ok(Placeholder::Period),
+            "algorithm" => Ok(Placeholder::Algorithm),
+            _ => Err(format!(
+                "Unknown placeholder '{{{}}}', expected one of {{name}}, {{issuer}}, {{code}}, \
+                 {{next_code}}, {{remaining}}, {{period}} or {{algorithm}}",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+/// A parsed output template. `{{` and `}}` stand for literal braces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template(Vec<Piece>);
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    if !closed {
+                        return Err("Unclosed '{' in the output format".to_string());
+                    }
+                    let placeholder = name.parse()?;
+                    if !text.is_empty() {
+                        pieces.push(Piece::Text(std::mem::take(&mut text)));
+                    }
+                    pieces.push(Piece::Placeholder(placeholder));
+                }
+                '}' => return Err("Unmatched '}' in the output format".to_string()),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text));
+        }
+        Ok(Template(pieces))
+    }
+}
+
+/// What a template is filled in with. Values that don't apply to a token, like the period of a
+/// challenge-response token, are left empty.
+#[derive(Debug)]
+pub struct Values<'a> {
+    pub name: &'a str,
+    pub issuer: Option<&'a str>,
+    pub code: &'a str,
+    pub next_code: Option<&'a str>,
+    pub remaining: Option<u64>,
+    pub period: Option<u64>,
+    pub algorithm: String,
+}
+
+impl Template {
+    /// Whether the template shows the next code, which costs generating a second code
+    pub fn uses_next_code(&self) -> bool {
+        self.0.contains(&Piece::Placeholder(Placeholder::NextCode))
+    }
+
+    /// Fills in the template, with codes split into groups of `group_digits` if given
+    pub fn render(&self, values: &Values, group_digits: Option<usize>) -> String {
+        let mut rendered = String::new();
+        for piece in &self.0 {
+            match piece {
+                Piece::Text(text) => rendered += text,
+                Piece::Placeholder(placeholder) => {
+                    rendered += &match placeholder {
+                        Placeholder::Name => values.name.to_string(),
+                        Placeholder::Issuer => values.issuer.unwrap_or_default().to_string(),
+                        Placeholder::Code => group(values.code, group_digits),
+                        Placeholder::NextCode => values
+                            .next_code
+                            .map(|code| group(code, group_digits))
+                            .unwrap_or_default(),
+                        Placeholder::Remaining => to_string(values.remaining),
+                        Placeholder::Period => to_string(values.period),
+                        Placeholder::Algorithm => values.algorithm.clone(),
+                    }
+                }
+            }
+        }
+        rendered
+    }
+}
+
+fn to_string(value: Option<u64>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+/// Splits a code into space separated groups of `size` characters, like `123 456`
+pub fn group(code: &str, size: Option<usize>) -> String {
+    match size {
+        Some(size) if size > 0 => code
+            .chars()
+            .collect::<Vec<_>>()
+            .chunks(size)
+            .map(|chunk| chunk.iter().collect::<String>())
+            .collect::<Vec<_>>()
+            .join(" "),
+        _ => code.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates_are_filled_in() -> Result<(), String> {
+        let values = Values {
+            name: "github",
+            issuer: Some("GitHub"),
+            code: "123456",
+            next_code: Some("654321"),
+            remaining: Some(12),
+            period: Some(30),
+            algorithm: "sha1".to_string(),
+        };
+        let template: Template =
+            "{name} ({issuer}): {code} {remaining}/{period}s {{{algorithm}}}".parse()?;
+        assert!(!template.uses_next_code());
+        assert_eq!(
+            template.render(&values, None),
+            "github (GitHub): 123456 12/30s {sha1}"
+        );
+
+        let template: Template = "{code} then {next_code}".parse()?;
+        assert!(template.uses_next_code());
+        assert_eq!(template.render(&values, Some(3)), "123 456 then 654 321");
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        assert!("{nmae}".parse::<Template>().is_err());
+        assert!("{code".parse::<Template>().is_err());
+        assert!("code}".parse::<Template>().is_err());
+        assert!("{{code".parse::<Template>().is_ok());
+    }
+
+    #[test]
+    fn codes_are_grouped_from_the_left() {
+        assert_eq!(group("12345678", Some(4)), "1234 5678");
+        assert_eq!(group("1234567", Some(3)), "123 456 7");
+        assert_eq!(group("123456", Some(0)), "123456");
+        assert_eq!(group("123456", None), "123456");
+    }
+}
@@ -1,85 +1,595 @@
 use std::error::Error;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-#[cfg(feature = "copy")]
-use clipboard::{ClipboardContext, ClipboardProvider};
 use structopt::StructOpt;
 
-use cli::{Command, Options};
+use batch::BatchFormat;
+use cli::{Command, Helper, Options};
+use format::Template;
+#[cfg(unix)]
+use otp::agent;
 use otp::{
     self,
-    config::{self, Config},
-    TotpResult,
+    config::{self, ClipboardConfig, Config, ListFilter, SecretLocation, TotpOptions},
+    encoding::{decode_secret, SecretEncoding},
+    migrate,
+    ocra::{self, OcraInput},
+    passphrase,
+    secret::{SecretBytes, SecretString},
+    TotpError, TotpResult,
 };
 
+#[cfg(any(feature = "copy", unix))]
+mod base64;
+mod batch;
 mod cli;
+mod completions;
+#[cfg(feature = "copy")]
+mod copy;
+mod format;
+mod man;
+#[cfg(unix)]
+mod openvpn;
+mod pick;
+mod typing;
 
 fn main() -> Result<(), Box<dyn Error>> {
+    if let Some(helper) = Helper::from_args_if_helper() {
+        return run_helper(helper);
+    }
     let opts: Options = Options::from_args();
 
     let config_dir = config::default_config_dir();
     let config = config::load_config(&config_dir)?;
 
-    match opts.command()? {
-        Command::GenerateToken { name } => generate_token(opts, config, name),
-        Command::ListSecrets { prefix } => {
-            let secrets = otp::list_secrets(config, prefix)?;
-            for sec in secrets {
-                println!("- {}", sec);
+    let command = opts.command()?;
+    if let Some(name) = command.single_token() {
+        let name = name.clone();
+        return generate_token(opts, config, config_dir, name);
+    }
+    match command {
+        Command::GenerateToken {
+            names,
+            all,
+            tag,
+            output,
+        } => {
+            if opts.with_pin {
+                return Err(Box::new(TotpError::of(
+                    "--with-pin only works when generating a single token",
+                )));
+            }
+            let names = if all || tag.is_some() {
+                let filter = ListFilter {
+                    tag,
+                    ..ListFilter::default()
+                };
+                // Challenge-response tokens have no code to show without a challenge
+                otp::list_secrets(&config, &filter)?
+                    .into_iter()
+                    .filter(|name| {
+                        config
+                            .lookup(name)
+                            .is_ok_and(|options| options.algorithm().period().is_some())
+                    })
+                    .collect()
+            } else {
+                names
+            };
+            let output = output.unwrap_or(BatchFormat::Table);
+            generate_tokens(&config, &config_dir, &names, output)
+        }
+        Command::OcraResponse {
+            name,
+            challenge,
+            counter,
+            session,
+        } => {
+            let session = session
+                .map(|session| decode_secret(&session, SecretEncoding::Hex))
+                .transpose()?;
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+            let input = OcraInput {
+                challenge: &challenge,
+                counter,
+                session: session.as_ref().map(SecretBytes::expose),
+                time_since_epoch: Some(now),
+                ..OcraInput::default()
+            };
+            let code = ocra::stored_response(&name, config.lookup(&name)?, &input)?;
+            let formatted = format_code(&opts, &config, &name, &code, now)?;
+            output_code(&opts, config.clipboard(), &code, &formatted)?;
+            record_usage(&config_dir, &[&name]);
+            Ok(())
+        }
+        Command::Pick { launcher } => {
+            let names = otp::list_secrets(&config, &ListFilter::default())?;
+            let choices: Vec<String> = names
+                .iter()
+                .map(|name| Ok(format!("{}{}", name, describe(config.lookup(name)?))))
+                .collect::<TotpResult<_>>()?;
+            match pick::choose(&launcher, &choices)? {
+                Some(picked) => {
+                    let name = names[picked].clone();
+                    generate_token(opts, config, config_dir, name)
+                }
+                None => Ok(()),
+            }
+        }
+        Command::Completions { shell } => completions::generate(shell, &mut std::io::stdout()),
+        Command::Man { out_dir: None } => {
+            print!("{}", man::pages()?[0].source);
+            Ok(())
+        }
+        Command::Man { out_dir: Some(dir) } => {
+            for path in man::write_pages(dir)? {
+                println!("- {}", path);
             }
             Ok(())
         }
-        Command::AddSecret { name, secret } => {
-            otp::add_totp_secret(config, config_dir, &name, secret.replace(" ", ""))?;
+        Command::ListSecrets {
+            prefix,
+            tag,
+            issuer,
+        } => {
+            let filter = ListFilter {
+                prefix,
+                tag,
+                issuer,
+            };
+            for name in otp::list_secrets(&config, &filter)? {
+                let options = config.lookup(&name)?;
+                println!("- {}{}", name, describe(options));
+                if opts.verbosity > 0 {
+                    print_details(options);
+                }
+            }
+            Ok(())
+        }
+        Command::AddSecret {
+            name,
+            secret,
+            encoding,
+            algorithm,
+            pin,
+            suite,
+            metadata,
+        } => {
+            let input = otp::SecretInput {
+                secret: SecretString::new(secret),
+                encoding,
+                algorithm,
+                pin: pin.map(SecretString::new),
+                ocra_suite: suite,
+            };
+            otp::add_totp_secret(
+                config,
+                config_dir,
+                &name,
+                input,
+                metadata.into_update(Vec::new()),
+            )?;
+            Ok(())
+        }
+        Command::EditSecret {
+            name,
+            metadata,
+            pin,
+            pin_file,
+            untag,
+        } => {
+            let pin = new_pin(pin, pin_file)?;
+            let config = otp::edit_secret(config, &config_dir, &name, metadata.into_update(untag))?;
+            if let Some(pin) = pin {
+                otp::set_pin(config, config_dir, &name, &pin)?;
+            }
             Ok(())
         }
-        #[cfg(feature = "ras_stoken")]
-        Command::ImportStoken { name, path, pin } => {
-            otp::add_stoken(&config, config_dir, &name, path, &pin)?;
+        #[cfg(feature = "rsa_stoken")]
+        Command::ImportStoken {
+            name,
+            token,
+            pin,
+            password,
+            device_id,
+            digits,
+        } => {
+            let options = otp::securid::ImportOptions {
+                pin,
+                password,
+                device_id,
+                digits,
+            };
+            otp::add_stoken(&config, config_dir, &name, &token, &options)?;
             Ok(())
         }
-        Command::DeleteSecret { name } => {
-            otp::delete_secret(config, config_dir, name)?;
+        Command::RenameSecret { old, new } => {
+            otp::rename_secret(config, config_dir, &old, &new)?;
+            Ok(())
+        }
+        Command::CopySecret { src, dst } => {
+            otp::copy_secret(config, config_dir, &src, &dst)?;
+            Ok(())
+        }
+        Command::DeleteSecret { name, yes } => {
+            config.lookup(&name)?;
+            if yes || confirm(&format!("Delete '{}'?", name))? {
+                otp::delete_secret(config, config_dir, name)?;
+            }
             Ok(())
         }
         #[cfg(feature = "keychain")]
-        Command::UseKeychain => {
-            otp::migrate_secrets_to_keychain(config, config_dir)?;
+        Command::CollectGarbage { dry_run, yes } => {
+            let orphans = otp::orphaned_keychain_secrets(&config)?;
+            if orphans.is_empty() {
+                println!("No orphaned keychain secrets found");
+                return Ok(());
+            }
+            for name in &orphans {
+                println!("- {}", name);
+            }
+            if dry_run {
+                return Ok(());
+            }
+            if yes || confirm(&format!("Remove {} orphaned secret(s)?", orphans.len()))? {
+                for name in orphans {
+                    otp::remove_orphaned_keychain_secret(&name)?;
+                }
+            }
+            Ok(())
+        }
+        #[cfg(unix)]
+        Command::Agent {
+            socket,
+            idle_timeout,
+            foreground,
+        } => {
+            let socket = socket.unwrap_or_else(agent::default_socket_path);
+            let listener = agent::listen(&socket)?;
+            // Fork before unlocking, and only tell the shell about the socket once the agent
+            // is ready to answer on it
+            let daemon = match foreground {
+                true => None,
+                false => match agent::daemonize()? {
+                    Some(daemon) => Some(daemon),
+                    None => return print_agent_socket(&socket),
+                },
+            };
+            let unlocked = agent::Agent::unlock(&config_dir, Duration::from_secs(idle_timeout));
+            let (mut agent, failures) = match unlocked {
+                Ok(unlocked) => unlocked,
+                Err(e) => {
+                    std::fs::remove_file(&socket)?;
+                    return Err(e);
+                }
+            };
+            for (name, reason) in failures {
+                eprintln!("Unable to unlock '{}': {}", name, reason);
+            }
+            match daemon {
+                Some(daemon) => daemon.detach()?,
+                None => print_agent_socket(&socket)?,
+            }
+            agent.serve(listener, socket)
+        }
+        #[cfg(unix)]
+        Command::OpenVpn {
+            name,
+            username,
+            management,
+            management_password_file,
+            remove_after,
+            command,
+        } => {
+            let options = config.lookup(&name)?;
+            let login = openvpn::Login {
+                username: username
+                    .or_else(|| options.account().cloned())
+                    .ok_or_else(|| {
+                        TotpError::of(
+                            "No VPN user name, pass --username or set the entry's account",
+                        )
+                    })?,
+                pin: otp::stored_pin(&name, &config)?,
+                pin_position: options.pin_position(),
+            };
+            let code = || {
+                otp::token_at_time(
+                    &name,
+                    &config,
+                    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?,
+                )
+            };
+            match management {
+                Some(address) => {
+                    let password = management_password_file
+                        .map(passphrase::read_passphrase_file)
+                        .transpose()?;
+                    openvpn::manage_at(&address, &login, password.as_ref(), code)?;
+                }
+                None => {
+                    let auth_file = openvpn::AuthFile::create(&login, &code()?)?;
+                    openvpn::run_with_auth_file(
+                        &command,
+                        auth_file,
+                        Duration::from_secs(remove_after),
+                    )?;
+                }
+            }
+            record_usage(&config_dir, &[&name]);
             Ok(())
         }
+        Command::ChangePassphrase {
+            new_passphrase_file,
+            remove,
+        } => {
+            let new_passphrase = match (remove, new_passphrase_file) {
+                (true, _) => None,
+                (false, Some(path)) => Some(passphrase::read_passphrase_file(path)?),
+                (false, None) => Some(prompt_new_passphrase()?),
+            };
+            otp::change_passphrase(config, config_dir, new_passphrase)?;
+            Ok(())
+        }
+        Command::MigrateSecrets { to, names } => migrate(config, config_dir, &to, &names),
+        #[cfg(feature = "keychain")]
+        Command::UseKeychain => migrate(config, config_dir, &SecretLocation::KeyChain, &[]),
     }
 }
 
-#[cfg(feature = "copy")]
-fn copy_to_clipboard(code: &str) -> TotpResult<()> {
-    let mut clipboard: ClipboardContext = ClipboardProvider::new()?;
-    clipboard.set_contents(code.to_string())?;
+fn run_helper(helper: Helper) -> TotpResult<()> {
+    match helper {
+        Helper::CompleteNames => {
+            let config = config::load_config(config::default_config_dir())?;
+            for name in otp::list_secrets(&config, &ListFilter::default())? {
+                println!("{}", name);
+            }
+            Ok(())
+        }
+        #[cfg(feature = "copy")]
+        Helper::HoldClipboard {
+            backend,
+            clear_after,
+            restore,
+        } => copy::hold_code(backend, Duration::from_secs(clear_after), restore),
+    }
+}
+
+fn migrate(
+    config: Config,
+    config_dir: PathBuf,
+    to: &SecretLocation,
+    names: &[String],
+) -> TotpResult<()> {
+    let (_, report) = migrate::migrate_secrets(config, config_dir, to, names)?;
+    for (name, status) in &report.accounts {
+        println!("- {}: {}", name, status);
+    }
+
+    if report.has_failures() {
+        return Err(Box::new(TotpError::of("Some secrets failed to migrate")));
+    }
+    Ok(())
+}
+
+/// Asks a yes/no question on the terminal, defaulting to no
+/// Prints the shell commands that point clients at the agent listening on `socket`
+#[cfg(unix)]
+fn print_agent_socket(socket: &Path) -> TotpResult<()> {
+    println!(
+        "{}={}; export {};",
+        agent::AGENT_SOCK_ENV,
+        socket.display(),
+        agent::AGENT_SOCK_ENV
+    );
+    std::io::stdout().flush()?;
     Ok(())
 }
 
-fn generate_token(opts: Options, config: Config, name: String) -> TotpResult<()> {
-    let code = match otp::token(&name, config) {
-        Ok(token) => token,
-        Err(e) => {
-            println!("Error: {}", e);
-            println!(
-                "a TOTP config named '{}' was not found, did you add a secret with that name?",
-                name
-            );
-            Options::clap().print_help()?;
-            return Ok(());
+fn confirm(prompt: &str) -> TotpResult<bool> {
+    print!("{} [y/N] ", prompt);
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes" | "YES" | "Yes"))
+}
+
+/// The PIN or password given to `edit`, prompted for when `--pin` has no value or read from the
+/// first line of `--pin-file`
+fn new_pin(
+    pin: Option<Option<String>>,
+    pin_file: Option<PathBuf>,
+) -> TotpResult<Option<SecretString>> {
+    match (pin, pin_file) {
+        (Some(Some(pin)), _) => Ok(Some(SecretString::new(pin))),
+        (Some(None), _) => {
+            let pin = rpassword::prompt_password("New PIN or password: ")?;
+            Ok(Some(SecretString::new(pin)))
+        }
+        (None, Some(path)) => {
+            let contents = SecretString::new(std::fs::read_to_string(path)?);
+            Ok(Some(SecretString::from(
+                contents.expose().lines().next().unwrap_or(""),
+            )))
         }
+        (None, None) => Ok(None),
+    }
+}
+
+/// Prompts for a new passphrase twice on the terminal
+fn prompt_new_passphrase() -> TotpResult<SecretString> {
+    let passphrase = SecretString::new(rpassword::prompt_password("New passphrase: ")?);
+    if passphrase.is_empty() {
+        return Err(Box::new(TotpError::of("The passphrase is empty")));
+    }
+    let repeated = SecretString::new(rpassword::prompt_password("Repeat new passphrase: ")?);
+    if passphrase != repeated {
+        return Err(Box::new(TotpError::of("The passphrases don't match")));
+    }
+    Ok(passphrase)
+}
+
+/// Formats the issuer, account and tags of an entry for display next to its name
+fn describe(options: &TotpOptions) -> String {
+    let mut description = String::new();
+    match (options.issuer(), options.account()) {
+        (Some(issuer), Some(account)) => description += &format!(" ({}: {})", issuer, account),
+        (Some(issuer), None) => description += &format!(" ({})", issuer),
+        (None, Some(account)) => description += &format!(" ({})", account),
+        (None, None) => {}
+    }
+    if !options.tags().is_empty() {
+        description += &format!(" [{}]", options.tags().join(", "));
+    }
+    description
+}
+
+fn print_details(options: &TotpOptions) {
+    if let Some(notes) = options.notes() {
+        println!("    notes: {}", notes);
+    }
+    if let Some(created_at) = options.created_at() {
+        println!("    created: {}", created_at);
+    }
+    if let Some(last_used_at) = options.last_used_at() {
+        println!("    last used: {}", last_used_at);
+    }
+}
+
+fn generate_token(
+    opts: Options,
+    config: Config,
+    config_dir: PathBuf,
+    name: String,
+) -> TotpResult<()> {
+    if let Err(e) = config.lookup(&name) {
+        println!("Error: {}", e);
+        println!(
+            "a TOTP config named '{}' was not found, did you add a secret with that name?",
+            name
+        );
+        Options::clap().print_help()?;
+        return Ok(());
+    }
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let code = otp::token_at_time(&name, &config, now)?;
+    let code = if opts.with_pin {
+        otp::with_pin(&name, &config, &code)?
+    } else {
+        code
     };
 
+    let formatted = format_code(&opts, &config, &name, &code, now)?;
+    output_code(&opts, config.clipboard(), &code, &formatted)?;
+    record_usage(&config_dir, &[&name]);
+    Ok(())
+}
+
+/// Records that codes were used, once they have been output. The timestamps are only
+/// informational, so failing to write them is reported without failing the command.
+fn record_usage(config_dir: &Path, names: &[&str]) {
+    if let Err(e) = otp::record_usage(config_dir, names) {
+        eprintln!("otpcli: unable to record when codes were last used: {}", e);
+    }
+}
+
+/// Prints the codes of several secrets, failing once they're printed if any couldn't be
+/// generated
+fn generate_tokens(
+    config: &Config,
+    config_dir: &Path,
+    names: &[String],
+    output: BatchFormat,
+) -> TotpResult<()> {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let rows = batch::rows(config, otp::tokens_at_time(names, config, now), now);
+    print!("{}", batch::render(&rows, output)?);
+    let generated: Vec<&str> = rows
+        .iter()
+        .filter(|row| row.error.is_none())
+        .map(|row| row.name.as_str())
+        .collect();
+    record_usage(config_dir, &generated);
+
+    if rows.iter().any(|row| row.error.is_some()) {
+        return Err(Box::new(TotpError::of("Some tokens failed to generate")));
+    }
+    Ok(())
+}
+
+/// Formats a code for printing with the `--format` template, or the config's default one
+fn format_code(
+    opts: &Options,
+    config: &Config,
+    name: &str,
+    code: &str,
+    now: Duration,
+) -> TotpResult<String> {
+    // Grouping would split up the PIN of a combined credential
+    let group_digits = if opts.with_pin {
+        None
+    } else {
+        opts.group_digits.or_else(|| config.output().group_digits())
+    };
+    let template: Template = match (&opts.format, config.output().format()) {
+        (Some(template), _) => template.clone(),
+        (None, Some(template)) => template.parse()?,
+        (None, None) => return Ok(format::group(code, group_digits)),
+    };
+
+    let options = config.lookup(name)?;
+    let period = options.algorithm().period();
+    let next_code = match period {
+        Some(period) if template.uses_next_code() => {
+            let next_code = otp::token_at_time(name, config, now + period)?;
+            // Either can be used to log in, so both are combined the same way
+            if opts.with_pin {
+                Some(otp::with_pin(name, config, &next_code)?)
+            } else {
+                Some(next_code)
+            }
+        }
+        _ => None,
+    };
+    let values = format::Values {
+        name,
+        issuer: options.issuer().map(String::as_str),
+        code,
+        next_code: next_code.as_deref(),
+        remaining: period.map(|period| period.as_secs() - now.as_secs() % period.as_secs()),
+        period: period.map(|period| period.as_secs()),
+        algorithm: options.algorithm().to_string(),
+    };
+    Ok(template.render(&values, group_digits))
+}
+
+/// Copies or types `code` as asked, and prints it as `formatted`
+fn output_code(
+    opts: &Options,
+    _clipboard: &ClipboardConfig,
+    code: &str,
+    formatted: &str,
+) -> TotpResult<()> {
     #[cfg(feature = "copy")]
     if opts.copy_to_clipboard() {
-        copy_to_clipboard(&code)?;
+        copy::copy_code(code, opts.clipboard_backend(), _clipboard)?;
+    }
+
+    if opts.type_code {
+        typing::type_code(
+            code,
+            opts.typing_backend(),
+            Duration::from_millis(opts.type_delay),
+        )?;
     }
 
     if opts.end_with_newline {
-        println!("{}", code);
+        println!("{}", formatted);
     } else {
-        print!("{}", code);
+        print!("{}", formatted);
         std::io::stdout().flush()?;
     }
 
@@ -0,0 +1,269 @@
+//! Generates roff man pages from the CLI definition: `otpcli(1)` for the top-level command and
+//! `otpcli-<subcommand>(1)` for each subcommand.
+//!
+//! clap 2 has no public API for walking its arguments, so the pages are built from the help it
+//! prints, rendered unwrapped and with each description on its own lines.
+use std::fs;
+use std::path::Path;
+
+use structopt::clap::{AppSettings, ErrorKind};
+use structopt::StructOpt;
+
+use crate::cli::Options;
+use otp::{TotpError, TotpResult};
+
+/// A man page's file name and its roff source
+pub struct ManPage {
+    pub file_name: String,
+    pub source: String,
+}
+
+/// The man pages for the top-level command and each of its subcommands
+pub fn pages() -> TotpResult<Vec<ManPage>> {
+    let help = Help::of(&[])?;
+    let version = help.title.split_whitespace().nth(1).unwrap_or_default();
+
+    let mut pages = vec![page(&help, &[], version)];
+    for subcommand in help.subcommands() {
+        pages.push(page(&Help::of(&[subcommand])?, &[subcommand], version));
+    }
+    Ok(pages)
+}
+
+/// Writes every man page into `dir`, returning the paths written
+pub fn write_pages<P: AsRef<Path>>(dir: P) -> TotpResult<Vec<String>> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    let mut written = Vec::new();
+    for page in pages()? {
+        let path = dir.join(&page.file_name);
+        fs::write(&path, page.source)?;
+        written.push(path.display().to_string());
+    }
+    Ok(written)
+}
+
+/// A command's `--help`, split into its parts
+struct Help {
+    /// The first line, with the command name and, for the top-level command, the version
+    title: String,
+    about: String,
+    usage: String,
+    /// The argument sections, e.g. `FLAGS`, each with the terms it describes and their help
+    sections: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl Help {
+    /// The help of the subcommand at `path`, or of otpcli itself when it is empty
+    fn of(path: &[&str]) -> TotpResult<Help> {
+        let app = Options::clap()
+            .set_term_width(0)
+            .global_settings(&[AppSettings::NextLineHelp, AppSettings::ColorNever]);
+        let mut args = vec!["otpcli"];
+        args.extend_from_slice(path);
+        args.push("--help");
+        match app.get_matches_from_safe(args) {
+            Err(e) if e.kind == ErrorKind::HelpDisplayed => Ok(Help::parse(&e.message)),
+            _ => Err(Box::new(TotpError::of(
+                "Unable to render the help of a command",
+            ))),
+        }
+    }
+
+    fn parse(help: &str) -> Help {
+        let mut lines = help.lines();
+        let title = lines.next().unwrap_or_default().trim().to_string();
+        let mut about = Vec::new();
+        let mut usage = String::new();
+        let mut sections: Vec<(String, Vec<(String, String)>)> = Vec::new();
+        while let Some(line) = lines.next() {
+            let indent = line.len() - line.trim_start().len();
+            let line = line.trim();
+            match (line, indent, sections.last_mut()) {
+                ("", _, _) => {}
+                ("USAGE:", 0, _) => usage = lines.next().unwrap_or_default().trim().to_string(),
+                (heading, 0, _) if usage.is_empty() => about.push(heading),
+                (heading, 0, _) => {
+                    let heading = heading.trim_end_matches(':').to_string();
+                    sections.push((heading, Vec::new()));
+                }
+                // Descriptions are indented further than the terms they describe
+                (description, 9..=usize::MAX, Some((_, entries))) => {
+                    if let Some((_, help)) = entries.last_mut() {
+                        if !help.is_empty() {
+                            help.push('\n');
+                        }
+                        help.push_str(description);
+                    }
+                }
+                (term, _, Some((_, entries))) => entries.push((term.to_string(), String::new())),
+                (_, _, None) => {}
+            }
+        }
+        Help {
+            title,
+            about: about.join("\n"),
+            usage,
+            sections,
+        }
+    }
+
+    fn section(&self, heading: &str) -> &[(String, String)] {
+        self.sections
+            .iter()
+            .find(|(name, _)| name == heading)
+            .map_or(&[], |(_, entries)| entries)
+    }
+
+    /// The subcommands, leaving out clap's `help`
+    fn subcommands(&self) -> Vec<&str> {
+        self.section("SUBCOMMANDS")
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .filter(|name| *name != "help")
+            .collect()
+    }
+}
+
+fn page(help: &Help, path: &[&str], version: &str) -> ManPage {
+    let mut command = vec!["otpcli"];
+    command.extend_from_slice(path);
+    let title = command.join("-");
+
+    let mut source = format!(
+        ".TH {} 1 \"\" \"otpcli {}\" \"User Commands\"\n",
+        escape(&title.to_uppercase()),
+        escape(version)
+    );
+    let summary = help.about.lines().next().unwrap_or_default();
+    source += &format!(".SH NAME\n{} \\- {}\n", escape(&title), escape(summary));
+    source += &format!(".SH SYNOPSIS\n{}\n", synopsis(&help.usage, &command));
+    if help.about.lines().count() > 1 {
+        source += &format!(".SH DESCRIPTION\n{}\n", escape(&help.about));
+    }
+
+    let mut options = String::new();
+    for (term, description) in help.section("FLAGS").iter().chain(help.section("OPTIONS")) {
+        options += &format!(".TP\n{}\n{}\n", switches(term), escape(description));
+    }
+    if !options.is_empty() {
+        source += &format!(".SH OPTIONS\n{}", options);
+    }
+
+    let positionals = help.section("ARGS");
+    if !positionals.is_empty() {
+        source += ".SH ARGUMENTS\n";
+        for (term, description) in positionals {
+            source += &format!(".TP\n{}\n{}\n", switches(term), escape(description));
+        }
+    }
+
+    let subcommands: Vec<_> = help
+        .section("SUBCOMMANDS")
+        .iter()
+        .filter(|(name, _)| name != "help")
+        .collect();
+    if !subcommands.is_empty() {
+        source += ".SH SUBCOMMANDS\n";
+        for (name, description) in subcommands {
+            source += &format!(
+                ".TP\n\\fB{}\\-{}\\fR(1)\n{}\n",
+                escape(&title),
+                escape(name),
+                escape(description)
+            );
+        }
+    }
+
+    if !path.is_empty() {
+        source += ".SH SEE ALSO\n\\fBotpcli\\fR(1)\n";
+    }
+
+    ManPage {
+        file_name: format!("{}.1", title),
+        source,
+    }
+}
+
+/// Sets the command in bold and the arguments of `usage` in italics
+fn synopsis(usage: &str, command: &[&str]) -> String {
+    let command = command.join(" ");
+    let arguments = usage.strip_prefix(command.as_str()).unwrap_or(usage);
+    let arguments = arguments
+        .split_whitespace()
+        .map(|argument| {
+            let name = argument.trim_matches(&['[', ']', '<', '>', '.'][..]);
+            match name {
+                "" => escape(argument),
+                _ => argument.replacen(name, &format!("\\fI{}\\fR", escape(name)), 1),
+            }
+        })
+        .collect::<Vec<_>>();
+    format!("\\fB{}\\fR {}", escape(&command), arguments.join(" "))
+        .trim_end()
+        .to_string()
+}
+
+/// Sets the switches of a help term, e.g. `-n, --newline` or `--tag <tag>`, in bold and its
+/// value in italics
+fn switches(term: &str) -> String {
+    term.split_whitespace()
+        .map(|word| {
+            if word.starts_with('<') {
+                format!("\\fI{}\\fR", escape(word))
+            } else {
+                let switch = word.trim_end_matches(',');
+                format!("\\fB{}\\fR{}", escape(switch), &word[switch.len()..])
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Escapes text for roff: backslashes and hyphens, and lines that would start a request
+fn escape(text: &str) -> String {
+    let escaped = text.replace('\\', "\\e").replace('-', "\\-");
+    escaped
+        .lines()
+        .map(|line| {
+            if line.starts_with('.') || line.starts_with('\'') {
+                format!("\\&{}", line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_visible_command_gets_a_page() -> TotpResult<()> {
+        let pages = pages()?;
+        let names: Vec<&str> = pages.iter().map(|page| page.file_name.as_str()).collect();
+        assert_eq!(names[0], "otpcli.1");
+        assert!(names.contains(&"otpcli-add.1"));
+        assert!(names.contains(&"otpcli-list.1"));
+        assert!(!names.iter().any(|name| name.contains("__")));
+
+        let list = &pages[names
+            .iter()
+            .position(|name| *name == "otpcli-list.1")
+            .unwrap()];
+        assert!(list.source.starts_with(".TH OTPCLI\\-LIST 1 "));
+        assert!(list
+            .source
+            .contains("otpcli\\-list \\- List the TOTP secrets"));
+        assert!(list.source.contains("\\fB\\-\\-tag\\fR \\fI<tag>\\fR"));
+        Ok(())
+    }
+
+    #[test]
+    fn roff_requests_and_hyphens_are_escaped() {
+        assert_eq!(escape("a-b\\c"), "a\\-b\\ec");
+        assert_eq!(escape(".TH\n'x"), "\\&.TH\n\\&'x");
+    }
+}
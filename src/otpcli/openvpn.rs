@@ -0,0 +1,537 @@
+//! Logs in to OpenVPN with generated codes, either by handing it a temporary `auth-user-pass`
+//! file or by answering the password and challenge requests of its management interface.
+use std::fs::{self, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use crate::base64;
+use otp::config::PinPosition;
+use otp::secret::SecretString;
+use otp::{TotpError, TotpResult};
+
+/// How often a command given an auth file is checked for having exited
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long the management interface may stay silent before otpcli stops waiting for OpenVPN
+const MANAGEMENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What OpenVPN is logged in with
+pub struct Login {
+    pub username: String,
+    /// The stored PIN or password, which is sent along with the code
+    pub pin: Option<SecretString>,
+    pub pin_position: PinPosition,
+}
+
+impl Login {
+    /// The password of a plain login: the code, combined with the PIN when there is one
+    fn password(&self, code: &str) -> String {
+        match &self.pin {
+            Some(pin) => self.pin_position.combine(pin.expose(), code),
+            None => code.to_string(),
+        }
+    }
+}
+
+/// A file with a username and password for OpenVPN's `--auth-user-pass`, removed when dropped
+pub struct AuthFile(PathBuf);
+
+impl AuthFile {
+    /// Writes a file only the current user can read, in `$XDG_RUNTIME_DIR` when it is set
+    pub fn create(login: &Login, code: &str) -> TotpResult<AuthFile> {
+        let dir = std::env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir);
+        AuthFile::create_in(&dir, login, code)
+    }
+
+    fn create_in(dir: &Path, login: &Login, code: &str) -> TotpResult<AuthFile> {
+        let path = dir.join(format!("otpcli-openvpn-{}", std::process::id()));
+        // Refuses to follow a link someone else planted at the path
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        let auth_file = AuthFile(path);
+        file.write_all(format!("{}\n{}\n", login.username, login.password(code)).as_bytes())?;
+        Ok(auth_file)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for AuthFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Runs `command` with the auth file, whose path replaces a `{}` argument or is otherwise passed
+/// with `--auth-user-pass`. The file is removed once the command exits or `remove_after` has
+/// passed, whichever comes first.
+pub fn run_with_auth_file(
+    command: &[String],
+    auth_file: AuthFile,
+    remove_after: Duration,
+) -> TotpResult<()> {
+    let args = arguments(command, &auth_file.path().to_string_lossy());
+    let (program, args) = args
+        .split_first()
+        .ok_or_else(|| TotpError::of("No command to run with the auth file"))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .spawn()
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Box::new(TotpError::of(
+                "The command to run with the auth file wasn't found",
+            )) as Box<dyn std::error::Error>,
+            _ => Box::new(e),
+        })?;
+
+    let deadline = Instant::now() + remove_after;
+    let mut auth_file = Some(auth_file);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            auth_file = None;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+    drop(auth_file);
+
+    if !status.success() {
+        return Err(Box::new(TotpError::of("The OpenVPN command failed")));
+    }
+    Ok(())
+}
+
+fn arguments(command: &[String], path: &str) -> Vec<String> {
+    if command.iter().any(|arg| arg == "{}") {
+        command
+            .iter()
+            .map(|arg| match arg.as_str() {
+                "{}" => path.to_string(),
+                _ => arg.clone(),
+            })
+            .collect()
+    } else {
+        let mut args = command.to_vec();
+        args.push("--auth-user-pass".to_string());
+        args.push(path.to_string());
+        args
+    }
+}
+
+/// Connects to the management interface at `address`, a unix socket path or a TCP address like
+/// `127.0.0.1:7505`, and answers it until OpenVPN is connected
+pub fn manage_at<F>(
+    address: &str,
+    login: &Login,
+    management_password: Option<&SecretString>,
+    code: F,
+) -> TotpResult<()>
+where
+    F: FnMut() -> TotpResult<String>,
+{
+    match address.parse::<SocketAddr>() {
+        Ok(address) => {
+            let stream = TcpStream::connect(address)?;
+            stream.set_read_timeout(Some(MANAGEMENT_TIMEOUT))?;
+            manage(stream, login, management_password, code)
+        }
+        Err(_) => {
+            let stream = UnixStream::connect(address)?;
+            stream.set_read_timeout(Some(MANAGEMENT_TIMEOUT))?;
+            manage(stream, login, management_password, code)
+        }
+    }
+}
+
+/// A line OpenVPN sent on its management interface
+#[derive(Debug, PartialEq, Eq)]
+enum Message<'a> {
+    /// The management interface is protected with a password
+    PasswordPrompt,
+    Info,
+    Hold,
+    /// Asks for the username and password, with a static challenge if `static_challenge`
+    NeedAuth {
+        static_challenge: bool,
+    },
+    /// Asks for a password otpcli doesn't have, like a private key's
+    NeedOther(&'a str),
+    /// The server's dynamic challenge, which is answered when OpenVPN next asks for the password
+    Challenge {
+        state_id: String,
+        username: String,
+    },
+    AuthFailed,
+    Connected,
+    Exiting,
+    Fatal(&'a str),
+    Error(&'a str),
+    Other,
+}
+
+fn parse(line: &str) -> Message<'_> {
+    if line == "ENTER PASSWORD:" {
+        return Message::PasswordPrompt;
+    }
+    if line.starts_with(">INFO:") {
+        return Message::Info;
+    }
+    if line.starts_with(">HOLD:") {
+        return Message::Hold;
+    }
+    if let Some(error) = line.strip_prefix("ERROR:") {
+        return Message::Error(error.trim());
+    }
+    if let Some(fatal) = line.strip_prefix(">FATAL:") {
+        return Message::Fatal(fatal);
+    }
+    // Real-time notifications are prefixed, the answer to `state` isn't: `<time>,<state>,...`
+    let state = line.strip_prefix(">STATE:").or_else(|| {
+        let time = line.split(',').next()?;
+        Some(line).filter(|_| !time.is_empty() && time.bytes().all(|b| b.is_ascii_digit()))
+    });
+    if let Some(state) = state {
+        return match state.split(',').nth(1) {
+            Some("CONNECTED") => Message::Connected,
+            Some("EXITING") => Message::Exiting,
+            _ => Message::Other,
+        };
+    }
+    if let Some(request) = line.strip_prefix(">PASSWORD:Need '") {
+        return match request.split_once('\'') {
+            Some(("Auth", rest)) => Message::NeedAuth {
+                static_challenge: rest.contains(" SC:"),
+            },
+            Some((other, _)) => Message::NeedOther(other),
+            None => Message::Other,
+        };
+    }
+    if let Some(failure) = line.strip_prefix(">PASSWORD:Verification Failed: 'Auth'") {
+        return challenge(failure).unwrap_or(Message::AuthFailed);
+    }
+    Message::Other
+}
+
+/// Parses a dynamic challenge, like `['CRV1:R,E:<state id>:<base64 username>:<text>']`
+fn challenge(failure: &str) -> Option<Message<'static>> {
+    let crv1 = failure.trim().strip_prefix("['CRV1:")?.strip_suffix("']")?;
+    let mut fields = crv1.splitn(4, ':');
+    let _flags = fields.next()?;
+    let state_id = fields.next()?.to_string();
+    let username = String::from_utf8(base64::decode(fields.next()?)?).ok()?;
+    Some(Message::Challenge { state_id, username })
+}
+
+/// Quotes a value for a management interface command
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Answers the management interface on `stream` until OpenVPN is connected, sending a fresh code
+/// each time it asks for the password
+pub fn manage<S, F>(
+    stream: S,
+    login: &Login,
+    management_password: Option<&SecretString>,
+    mut code: F,
+) -> TotpResult<()>
+where
+    S: Read + Write,
+    F: FnMut() -> TotpResult<String>,
+{
+    let mut connection = Connection(BufReader::new(stream));
+    let mut challenge = None;
+    while let Some(line) = connection.next_line()? {
+        match parse(&line) {
+            Message::PasswordPrompt => match management_password {
+                Some(password) => connection.send(password.expose())?,
+                None => {
+                    return Err(Box::new(TotpError::of(
+                        "The management interface needs a password, pass \
+                         --management-password-file",
+                    )))
+                }
+            },
+            // Asks for the current state too, as OpenVPN may already be connected
+            Message::Info => {
+                connection.send("state on")?;
+                connection.send("state")?;
+            }
+            Message::Hold => connection.send("hold release")?,
+            Message::NeedAuth { static_challenge } => {
+                let (username, password) = match challenge.take() {
+                    Some((state_id, username)) => {
+                        (username, format!("CRV1::{}::{}", state_id, code()?))
+                    }
+                    None if static_challenge => {
+                        let pin = login.pin.as_ref().ok_or_else(|| {
+                            TotpError::of(
+                                "OpenVPN asks for a password and a code, but no PIN or \
+                                 password is stored, add one with `otpcli edit --pin`",
+                            )
+                        })?;
+                        let password = format!(
+                            "SCRV1:{}:{}",
+                            base64::encode(pin.expose().as_bytes()),
+                            base64::encode(code()?.as_bytes())
+                        );
+                        (login.username.clone(), password)
+                    }
+                    None => (login.username.clone(), login.password(&code()?)),
+                };
+                connection.send(&format!("username \"Auth\" {}", quote(&username)))?;
+                connection.send(&format!("password \"Auth\" {}", quote(&password)))?;
+            }
+            Message::NeedOther(kind) => {
+                return Err(
+                    format!("OpenVPN asks for a '{}' password otpcli can't give", kind).into(),
+                )
+            }
+            Message::Challenge { state_id, username } => challenge = Some((state_id, username)),
+            Message::AuthFailed => {
+                return Err(Box::new(TotpError::of("OpenVPN rejected the login")))
+            }
+            Message::Connected => return Ok(()),
+            Message::Exiting => {
+                return Err(Box::new(TotpError::of("OpenVPN exited before connecting")))
+            }
+            Message::Fatal(message) => return Err(format!("OpenVPN failed: {}", message).into()),
+            Message::Error(message) => {
+                return Err(format!("OpenVPN refused a command: {}", message).into())
+            }
+            Message::Other => {}
+        }
+    }
+    Err(Box::new(TotpError::of(
+        "OpenVPN closed the management interface before connecting",
+    )))
+}
+
+struct Connection<S>(BufReader<S>);
+
+impl<S: Read + Write> Connection<S> {
+    /// Reads the next line, or the password prompt, which doesn't end with a newline
+    fn next_line(&mut self) -> TotpResult<Option<String>> {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        loop {
+            let read = match self.0.read(&mut byte) {
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Err(Box::new(TotpError::of(
+                        "OpenVPN stopped answering on its management interface",
+                    )))
+                }
+                result => result?,
+            };
+            if read == 0 {
+                if line.is_empty() {
+                    return Ok(None);
+                }
+                break;
+            }
+            match byte[0] {
+                b'\n' => break,
+                b'\r' => {}
+                b => line.push(b),
+            }
+            if line == b"ENTER PASSWORD:" {
+                break;
+            }
+        }
+        Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+    }
+
+    fn send(&mut self, command: &str) -> TotpResult<()> {
+        let stream = self.0.get_mut();
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\n")?;
+        stream.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+    use std::os::unix::fs::PermissionsExt;
+    use std::thread;
+
+    fn login() -> Login {
+        Login {
+            username: "alice".to_string(),
+            pin: Some(SecretString::new("hunter2".to_string())),
+            pin_position: PinPosition::Prefix,
+        }
+    }
+
+    #[test]
+    fn auth_files_are_private_removed_and_passed_to_the_command() -> TotpResult<()> {
+        let dir = std::env::temp_dir();
+        let auth_file = AuthFile::create_in(&dir, &login(), "123456")?;
+        let path = auth_file.path().to_path_buf();
+        assert_eq!(fs::read_to_string(&path)?, "alice\nhunter2123456\n");
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+
+        drop(auth_file);
+        assert!(!path.exists());
+
+        let command = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            arguments(&command(&["openvpn", "--auth-user-pass", "{}"]), "/f"),
+            command(&["openvpn", "--auth-user-pass", "/f"])
+        );
+        assert_eq!(
+            arguments(&command(&["openvpn", "--config", "x.ovpn"]), "/f"),
+            command(&["openvpn", "--config", "x.ovpn", "--auth-user-pass", "/f"])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn messages_are_parsed() {
+        assert_eq!(
+            parse(">PASSWORD:Need 'Auth' username/password"),
+            Message::NeedAuth {
+                static_challenge: false
+            }
+        );
+        assert_eq!(
+            parse(">PASSWORD:Need 'Auth' username/password SC:1,Enter code"),
+            Message::NeedAuth {
+                static_challenge: true
+            }
+        );
+        assert_eq!(
+            parse(">PASSWORD:Need 'Private Key' password"),
+            Message::NeedOther("Private Key")
+        );
+        assert_eq!(
+            parse(">PASSWORD:Verification Failed: 'Auth' ['CRV1:R,E:abc123:YWxpY2U=:Enter code']"),
+            Message::Challenge {
+                state_id: "abc123".to_string(),
+                username: "alice".to_string()
+            }
+        );
+        assert_eq!(
+            parse(">PASSWORD:Verification Failed: 'Auth'"),
+            Message::AuthFailed
+        );
+        assert_eq!(
+            parse(">STATE:1700000000,CONNECTED,SUCCESS,10.8.0.2,1.2.3.4"),
+            Message::Connected
+        );
+        assert_eq!(
+            parse("1700000000,CONNECTED,SUCCESS,10.8.0.2,1.2.3.4"),
+            Message::Connected
+        );
+        assert_eq!(parse("1700000000,WAIT,,,,,,"), Message::Other);
+        assert_eq!(quote("a\"b\\c"), "\"a\\\"b\\\\c\"");
+    }
+
+    /// Plays OpenVPN's side of the management interface, checking what otpcli sends
+    fn server(stream: UnixStream, script: &'static [(&'static str, &'static [&'static str])]) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        for (message, expected) in script {
+            writer.write_all(message.as_bytes()).unwrap();
+            for expected in expected.iter() {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                assert_eq!(line.trim_end(), *expected);
+            }
+        }
+    }
+
+    #[test]
+    fn static_and_dynamic_challenges_are_answered() -> TotpResult<()> {
+        let (client, server_end) = UnixStream::pair()?;
+        let server = thread::spawn(move || {
+            server(
+                server_end,
+                &[
+                    ("ENTER PASSWORD:", &["secret"]),
+                    (
+                        "SUCCESS: password is correct\n>INFO:OpenVPN Management Interface\n",
+                        &["state on", "state"],
+                    ),
+                    (
+                        "SUCCESS: real-time state notification set to ON\n\
+                         1700000000,WAIT,,,,,,\nEND\n",
+                        &[],
+                    ),
+                    (">HOLD:Waiting for hold release:0\n", &["hold release"]),
+                    (
+                        ">PASSWORD:Need 'Auth' username/password SC:1,Enter code\n",
+                        &[
+                            "username \"Auth\" \"alice\"",
+                            "password \"Auth\" \"SCRV1:aHVudGVyMg==:MTExMTEx\"",
+                        ],
+                    ),
+                    (
+                        ">PASSWORD:Verification Failed: 'Auth' ['CRV1:R,E:abc123:Ym9i:Enter code']\n\
+                         >PASSWORD:Need 'Auth' username/password\n",
+                        &[
+                            "username \"Auth\" \"bob\"",
+                            "password \"Auth\" \"CRV1::abc123::222222\"",
+                        ],
+                    ),
+                    (">STATE:1700000000,CONNECTED,SUCCESS,10.8.0.2\n", &[]),
+                ],
+            )
+        });
+
+        let mut codes = vec!["222222", "111111"];
+        manage(
+            client,
+            &login(),
+            Some(&SecretString::new("secret".to_string())),
+            || Ok(codes.pop().unwrap().to_string()),
+        )?;
+        server.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn connected_openvpns_and_silent_interfaces_end_the_login() -> TotpResult<()> {
+        let (client, server_end) = UnixStream::pair()?;
+        let server = thread::spawn(move || {
+            server(
+                server_end,
+                &[
+                    (
+                        ">INFO:OpenVPN Management Interface\n",
+                        &["state on", "state"],
+                    ),
+                    (
+                        "SUCCESS: real-time state notification set to ON\n\
+                         1700000000,CONNECTED,SUCCESS,10.8.0.2,,,,\nEND\n",
+                        &[],
+                    ),
+                ],
+            )
+        });
+        manage(client, &login(), None, || Ok("111111".to_string()))?;
+        server.join().unwrap();
+
+        let (client, _server_end) = UnixStream::pair()?;
+        client.set_read_timeout(Some(Duration::from_millis(50)))?;
+        let error = manage(client, &login(), None, || Ok("111111".to_string()))
+            .expect_err("no answer from OpenVPN");
+        assert!(error.to_string().contains("stopped answering"), "{}", error);
+        Ok(())
+    }
+}
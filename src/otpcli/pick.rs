@@ -0,0 +1,119 @@
+//! Picks an entry with a menu program like dmenu, rofi or fzf.
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{ErrorKind, Write};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use otp::{TotpError, TotpResult};
+
+/// The menu program entries are picked with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Launcher {
+    Dmenu,
+    Rofi,
+    Fzf,
+    /// A shell command that reads choices from stdin and prints the chosen one, like
+    /// `wofi --dmenu`
+    Custom(String),
+}
+
+impl FromStr for Launcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dmenu" => Ok(Launcher::Dmenu),
+            "rofi" => Ok(Launcher::Rofi),
+            "fzf" => Ok(Launcher::Fzf),
+            "" => Err("The launcher command is empty".to_string()),
+            command => Ok(Launcher::Custom(command.to_string())),
+        }
+    }
+}
+
+impl Display for Launcher {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Launcher::Dmenu => write!(f, "dmenu"),
+            Launcher::Rofi => write!(f, "rofi"),
+            Launcher::Fzf => write!(f, "fzf"),
+            Launcher::Custom(command) => write!(f, "{}", command),
+        }
+    }
+}
+
+impl Launcher {
+    fn command(&self) -> Command {
+        let (program, args): (&str, &[&str]) = match self {
+            Launcher::Dmenu => ("dmenu", &["-i", "-p", "otpcli"]),
+            Launcher::Rofi => ("rofi", &["-dmenu", "-i", "-p", "otpcli"]),
+            Launcher::Fzf => ("fzf", &["--prompt", "otpcli> "]),
+            Launcher::Custom(command) => ("sh", &["-c", command]),
+        };
+        let mut command = Command::new(program);
+        command.args(args);
+        command
+    }
+}
+
+/// Shows `choices` in the launcher, returning the index of the one picked, or `None` if the
+/// launcher was dismissed
+pub fn choose(launcher: &Launcher, choices: &[String]) -> TotpResult<Option<usize>> {
+    // The launcher draws on the terminal (fzf) or its own window, so only stdin and stdout
+    // are piped
+    let mut child = launcher
+        .command()
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Box::new(TotpError::of("The launcher isn't installed"))
+                as Box<dyn std::error::Error>,
+            _ => Box::new(e),
+        })?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let written = choices
+        .iter()
+        .try_for_each(|choice| writeln!(stdin, "{}", choice));
+    drop(stdin);
+    match written {
+        // A launcher that's dismissed straight away may not read all the choices
+        Err(e) if e.kind() != ErrorKind::BrokenPipe => return Err(Box::new(e)),
+        _ => {}
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let picked = String::from_utf8(output.stdout)?;
+    let picked = picked.trim_end_matches(&['\r', '\n'][..]);
+    Ok(choices.iter().position(|choice| choice == picked))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn launchers_parse_from_their_names_or_a_command() {
+        assert_eq!("rofi".parse::<Launcher>(), Ok(Launcher::Rofi));
+        assert_eq!(
+            "wofi --dmenu".parse::<Launcher>(),
+            Ok(Launcher::Custom("wofi --dmenu".to_string()))
+        );
+        assert!("".parse::<Launcher>().is_err());
+    }
+
+    #[test]
+    fn the_picked_line_is_returned() -> TotpResult<()> {
+        let choices = vec!["github (GitHub) [work]".to_string(), "aws".to_string()];
+        let picked = choose(&Launcher::Custom("tail -n 1".to_string()), &choices)?;
+        assert_eq!(picked, Some(1));
+        assert_eq!(
+            choose(&Launcher::Custom("false".to_string()), &choices)?,
+            None
+        );
+        Ok(())
+    }
+}
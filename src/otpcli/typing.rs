@@ -0,0 +1,275 @@
+//! Types codes into the focused window as keystrokes, for forms that don't allow pasting.
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{ErrorKind, Write};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
+
+use otp::{TotpError, TotpResult};
+
+/// How keystrokes are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TypingBackend {
+    /// `xdotool`, for X11
+    Xdotool,
+    /// `wtype`, for Wayland compositors that support virtual keyboards
+    Wtype,
+    /// A virtual keyboard created through `/dev/uinput`, which works anywhere on Linux but needs
+    /// write access to it and assumes a US keyboard layout
+    Uinput,
+}
+
+impl FromStr for TypingBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "xdotool" => Ok(TypingBackend::Xdotool),
+            "wtype" => Ok(TypingBackend::Wtype),
+            "uinput" => Ok(TypingBackend::Uinput),
+            _ => Err(format!(
+                "Unknown typing backend '{}', expected one of xdotool, wtype or uinput",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for TypingBackend {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            TypingBackend::Xdotool => write!(f, "xdotool"),
+            TypingBackend::Wtype => write!(f, "wtype"),
+            TypingBackend::Uinput => write!(f, "uinput"),
+        }
+    }
+}
+
+impl TypingBackend {
+    /// Picks the backend for the current session's display server, falling back to uinput
+    pub fn detect() -> Self {
+        let is_set = |var: &str| std::env::var_os(var).is_some_and(|value| !value.is_empty());
+        if is_set("WAYLAND_DISPLAY") {
+            TypingBackend::Wtype
+        } else if is_set("DISPLAY") {
+            TypingBackend::Xdotool
+        } else {
+            TypingBackend::Uinput
+        }
+    }
+}
+
+/// Types `code` into the focused window, waiting `delay` between keystrokes
+pub fn type_code(code: &str, backend: TypingBackend, delay: Duration) -> TotpResult<()> {
+    let delay_ms = delay.as_millis().to_string();
+    // The code is passed on stdin to keep it out of the process list
+    match backend {
+        TypingBackend::Xdotool => run(
+            "xdotool",
+            &["type", "--delay", &delay_ms, "--file", "-"],
+            code,
+            "Typing with xdotool needs it to be installed",
+        ),
+        TypingBackend::Wtype => run(
+            "wtype",
+            &["-d", &delay_ms, "-"],
+            code,
+            "Typing with wtype needs it to be installed",
+        ),
+        TypingBackend::Uinput => uinput::type_code(code, delay),
+    }
+}
+
+fn run(program: &str, args: &[&str], input: &str, not_installed: &'static str) -> TotpResult<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Box::new(TotpError::of(not_installed)) as Box<dyn Error>,
+            _ => Box::new(e),
+        })?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(input.as_bytes())?;
+    if !child.wait()?.success() {
+        return Err(Box::new(TotpError::of("Unable to type the code")));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod uinput {
+    use std::fs::{File, OpenOptions};
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+
+    use otp::{TotpError, TotpResult};
+
+    const UI_SET_EVBIT: u64 = 0x4004_5564;
+    const UI_SET_KEYBIT: u64 = 0x4004_5565;
+    const UI_DEV_CREATE: u64 = 0x5501;
+    const UI_DEV_DESTROY: u64 = 0x5502;
+
+    const EV_SYN: u16 = 0x00;
+    const EV_KEY: u16 = 0x01;
+    const SYN_REPORT: u16 = 0;
+    const BUS_VIRTUAL: u16 = 0x06;
+    const KEY_LEFTSHIFT: u16 = 42;
+
+    /// How long the display server gets to notice the new keyboard before it's typed on
+    const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+    /// The key code for a character on a US layout, and whether it's typed with shift
+    fn key(c: char) -> Option<(u16, bool)> {
+        const DIGITS: &[u16] = &[11, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        const LETTERS: &[u16] = &[
+            30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, 50, 49, 24, 25, 16, 19, 31, 20, 22, 47,
+            17, 45, 21, 44,
+        ];
+        match c {
+            '0'..='9' => Some((DIGITS[c as usize - '0' as usize], false)),
+            'a'..='z' => Some((LETTERS[c as usize - 'a' as usize], false)),
+            'A'..='Z' => Some((LETTERS[c as usize - 'A' as usize], true)),
+            _ => None,
+        }
+    }
+
+    /// A virtual keyboard, removed again when dropped
+    struct Keyboard(File);
+
+    impl Keyboard {
+        fn create(keys: &[u16]) -> TotpResult<Keyboard> {
+            let file = OpenOptions::new()
+                .write(true)
+                .open("/dev/uinput")
+                .map_err(|e| {
+                    TotpError::of(if e.kind() == std::io::ErrorKind::PermissionDenied {
+                        "Typing with uinput needs write access to /dev/uinput"
+                    } else {
+                        "Unable to open /dev/uinput"
+                    })
+                })?;
+            let fd = file.as_raw_fd();
+            // SAFETY: these ioctls only take integer arguments
+            unsafe {
+                check(libc::ioctl(
+                    fd,
+                    UI_SET_EVBIT as _,
+                    libc::c_int::from(EV_KEY),
+                ))?;
+                for key in keys {
+                    check(libc::ioctl(fd, UI_SET_KEYBIT as _, libc::c_int::from(*key)))?;
+                }
+            }
+
+            // SAFETY: uinput_user_dev is plain data, for which all zeroes is valid
+            let mut device: libc::uinput_user_dev = unsafe { std::mem::zeroed() };
+            for (to, from) in device.name.iter_mut().zip(b"otpcli virtual keyboard") {
+                *to = *from as libc::c_char;
+            }
+            device.id.bustype = BUS_VIRTUAL;
+            // SAFETY: the slice covers exactly the device struct
+            (&file).write_all(unsafe { as_bytes(&device) })?;
+            // SAFETY: takes no argument
+            check(unsafe { libc::ioctl(fd, UI_DEV_CREATE as _) })?;
+            Ok(Keyboard(file))
+        }
+
+        fn emit(&self, kind: u16, code: u16, value: i32) -> TotpResult<()> {
+            // SAFETY: input_event is plain data, for which all zeroes is valid; the kernel
+            // fills in the time
+            let mut event: libc::input_event = unsafe { std::mem::zeroed() };
+            event.type_ = kind;
+            event.code = code;
+            event.value = value;
+            // SAFETY: the slice covers exactly the event struct
+            (&self.0).write_all(unsafe { as_bytes(&event) })?;
+            Ok(())
+        }
+
+        fn press(&self, key: u16, pressed: bool) -> TotpResult<()> {
+            self.emit(EV_KEY, key, pressed as i32)?;
+            self.emit(EV_SYN, SYN_REPORT, 0)
+        }
+    }
+
+    impl Drop for Keyboard {
+        fn drop(&mut self) {
+            // SAFETY: takes no argument
+            unsafe {
+                libc::ioctl(self.0.as_raw_fd(), UI_DEV_DESTROY as _);
+            }
+        }
+    }
+
+    unsafe fn as_bytes<T>(value: &T) -> &[u8] {
+        std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>())
+    }
+
+    fn check(result: libc::c_int) -> TotpResult<()> {
+        if result < 0 {
+            return Err(Box::new(std::io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    pub fn type_code(code: &str, delay: Duration) -> TotpResult<()> {
+        let keys = code
+            .chars()
+            .map(|c| key(c).ok_or_else(|| TotpError::of("The code can't be typed with uinput")))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut key_codes: Vec<u16> = keys.iter().map(|(key, _)| *key).collect();
+        key_codes.push(KEY_LEFTSHIFT);
+
+        let keyboard = Keyboard::create(&key_codes)?;
+        std::thread::sleep(SETTLE_TIME);
+        for (key, shift) in keys {
+            if shift {
+                keyboard.press(KEY_LEFTSHIFT, true)?;
+            }
+            keyboard.press(key, true)?;
+            keyboard.press(key, false)?;
+            if shift {
+                keyboard.press(KEY_LEFTSHIFT, false)?;
+            }
+            std::thread::sleep(delay);
+        }
+        // Give the last keystrokes time to be read before the keyboard goes away
+        std::thread::sleep(SETTLE_TIME);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn characters_map_to_us_layout_keys() {
+            assert_eq!(key('1'), Some((2, false)));
+            assert_eq!(key('0'), Some((11, false)));
+            assert_eq!(key('q'), Some((16, false)));
+            assert_eq!(key('M'), Some((50, true)));
+            assert_eq!(key('-'), None);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod uinput {
+    use std::time::Duration;
+
+    use otp::{TotpError, TotpResult};
+
+    pub fn type_code(_code: &str, _delay: Duration) -> TotpResult<()> {
+        Err(Box::new(TotpError::of(
+            "Typing with uinput is only supported on Linux",
+        )))
+    }
+}
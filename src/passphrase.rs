@@ -0,0 +1,533 @@
+//! Encrypts config-stored secrets in place with a master passphrase.
+//!
+//! Encrypted secrets and PINs are stored as `secret_enc` and `pin_enc` strings of the form
+//! `scrypt$<log2 N>$<salt>$<nonce>$<ciphertext>`, each part base32 encoded. The key is derived
+//! from the passphrase with scrypt and the secret is sealed with XChaCha20-Poly1305, with the
+//! entry name and field authenticated alongside it so sealed values can't be moved between them.
+//!
+//! The passphrase is read from the file named by `OTPCLI_PASSPHRASE_FILE`, or prompted for on
+//! the terminal, at most once per process.
+use std::path::Path;
+use std::sync::Mutex;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::config::{Config, SecretLocation, TotpOptions};
+use crate::secret::{SecretBytes, SecretString};
+use crate::{secrets, TotpConfigError, TotpError, TotpResult};
+
+/// The environment variable naming a file to read the passphrase from instead of prompting
+pub const PASSPHRASE_FILE_ENV: &str = "OTPCLI_PASSPHRASE_FILE";
+
+const SCHEME: &str = "scrypt";
+/// scrypt's cost parameters: N = 2^15 and r = 8 take around 32MiB and 100ms to derive a key
+const LOG_N: u8 = 15;
+const BLOCK_SIZE: u32 = 8;
+const PARALLELISM: u32 = 1;
+const KEY_LENGTH: usize = 32;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+
+static ALPHABET: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+/// The passphrase of this invocation, once it has been read
+static PASSPHRASE: Mutex<Option<SecretString>> = Mutex::new(None);
+/// Keys derived from the passphrase of this invocation, so each salt is only derived once
+static KEYS: Mutex<Vec<(KeyParams, SecretBytes)>> = Mutex::new(Vec::new());
+
+/// The field of an entry a sealed value is stored in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Field {
+    Secret,
+    Pin,
+}
+
+impl Field {
+    /// The data authenticated with a value sealed for this field of the entry `name`
+    fn associated_data(self, name: &str) -> Vec<u8> {
+        let field = match self {
+            Field::Secret => "secret",
+            Field::Pin => "pin",
+        };
+        format!("otpcli:{}:{}", field, name).into_bytes()
+    }
+}
+
+/// What a key is derived with, besides the passphrase
+#[derive(Clone, PartialEq, Eq)]
+struct KeyParams {
+    log_n: u8,
+    salt: Vec<u8>,
+}
+
+/// A parsed `secret_enc` or `pin_enc` value
+struct Sealed {
+    params: KeyParams,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+/// Encrypts secrets with a key derived from a passphrase
+pub(crate) struct Sealer {
+    params: KeyParams,
+    key: SecretBytes,
+}
+
+impl Sealer {
+    /// Derives a key from `passphrase` with a new random salt
+    pub(crate) fn new(passphrase: &SecretString) -> TotpResult<Self> {
+        Sealer::with_cost(passphrase, LOG_N)
+    }
+
+    fn with_cost(passphrase: &SecretString, log_n: u8) -> TotpResult<Self> {
+        let params = KeyParams {
+            log_n,
+            salt: random_bytes(SALT_LENGTH)?,
+        };
+        let key = derive_key(passphrase, &params)?;
+        Ok(Sealer { params, key })
+    }
+
+    /// The sealer that produced `sealed`, using the passphrase of this invocation
+    fn for_sealed(sealed: &str) -> TotpResult<Self> {
+        let params = Sealed::parse(sealed)?.params;
+        Ok(Sealer {
+            key: cached_key(&params)?,
+            params,
+        })
+    }
+
+    /// Encrypts `secret` as the `field` of the entry `name`
+    pub(crate) fn seal(
+        &self,
+        name: &str,
+        field: Field,
+        secret: &SecretString,
+    ) -> TotpResult<String> {
+        let nonce = random_bytes(NONCE_LENGTH)?;
+        let payload = Payload {
+            msg: secret.expose().as_bytes(),
+            aad: &field.associated_data(name),
+        };
+        let ciphertext = cipher(&self.key)?
+            .encrypt(XNonce::from_slice(&nonce), payload)
+            .map_err(|_| TotpError("Unable to encrypt secret"))?;
+        Ok(format!(
+            "{}${}${}${}${}",
+            SCHEME,
+            self.params.log_n,
+            base32::encode(ALPHABET, &self.params.salt),
+            base32::encode(ALPHABET, &nonce),
+            base32::encode(ALPHABET, &ciphertext)
+        ))
+    }
+}
+
+impl Sealed {
+    fn parse(sealed: &str) -> TotpResult<Self> {
+        let invalid = || TotpError("Encrypted secret is malformed");
+        let parts: Vec<&str> = sealed.trim().split('$').collect();
+        let (log_n, salt, nonce, ciphertext) = match parts.as_slice() {
+            [SCHEME, log_n, salt, nonce, ciphertext] => (log_n, salt, nonce, ciphertext),
+            [scheme, ..] if parts.len() == 5 => {
+                return Err(Box::new(TotpConfigError(format!(
+                    "Unsupported secret encryption '{}'",
+                    scheme
+                ))))
+            }
+            _ => return Err(Box::new(invalid())),
+        };
+        let decode = |part: &str| base32::decode(ALPHABET, part).ok_or_else(invalid);
+        let sealed = Sealed {
+            params: KeyParams {
+                log_n: log_n.parse().map_err(|_| invalid())?,
+                salt: decode(salt)?,
+            },
+            nonce: decode(nonce)?,
+            ciphertext: decode(ciphertext)?,
+        };
+        if sealed.nonce.len() != NONCE_LENGTH {
+            return Err(Box::new(invalid()));
+        }
+        Ok(sealed)
+    }
+
+    fn open(&self, key: &SecretBytes, name: &str, field: Field) -> TotpResult<SecretString> {
+        let payload = Payload {
+            msg: self.ciphertext.as_slice(),
+            aad: &field.associated_data(name),
+        };
+        let plaintext = cipher(key)?
+            .decrypt(XNonce::from_slice(&self.nonce), payload)
+            .map_err(|_| TotpError("Unable to decrypt secret, is the passphrase right?"))?;
+        Ok(SecretString::new(String::from_utf8(plaintext)?))
+    }
+}
+
+fn cipher(key: &SecretBytes) -> TotpResult<XChaCha20Poly1305> {
+    Ok(XChaCha20Poly1305::new_from_slice(key.expose())
+        .map_err(|_| TotpError("Encryption key has the wrong length"))?)
+}
+
+fn random_bytes(length: usize) -> TotpResult<Vec<u8>> {
+    let mut bytes = vec![0; length];
+    getrandom::getrandom(&mut bytes).map_err(|_| TotpError("Unable to generate random bytes"))?;
+    Ok(bytes)
+}
+
+fn derive_key(passphrase: &SecretString, params: &KeyParams) -> TotpResult<SecretBytes> {
+    let scrypt_params = scrypt::Params::new(params.log_n, BLOCK_SIZE, PARALLELISM, KEY_LENGTH)
+        .map_err(|_| TotpError("Invalid scrypt parameters"))?;
+    let mut output = vec![0; KEY_LENGTH];
+    let result = scrypt::scrypt(
+        passphrase.expose().as_bytes(),
+        &params.salt,
+        &scrypt_params,
+        &mut output,
+    );
+    let key = SecretBytes::new(output);
+    result.map_err(|_| TotpError("Unable to derive a key from the passphrase"))?;
+    Ok(key)
+}
+
+fn cached_key(params: &KeyParams) -> TotpResult<SecretBytes> {
+    let cached = KEYS
+        .lock()
+        .expect("passphrase key cache poisoned")
+        .iter()
+        .find(|(cached, _)| cached == params)
+        .map(|(_, key)| key.clone());
+    match cached {
+        Some(key) => Ok(key),
+        None => {
+            let key = derive_key(&passphrase()?, params)?;
+            KEYS.lock()
+                .expect("passphrase key cache poisoned")
+                .push((params.clone(), key.clone()));
+            Ok(key)
+        }
+    }
+}
+
+/// The passphrase of this invocation, read or prompted for the first time it is needed
+fn passphrase() -> TotpResult<SecretString> {
+    let mut cached = PASSPHRASE.lock().expect("passphrase cache poisoned");
+    if let Some(passphrase) = cached.as_ref() {
+        return Ok(passphrase.clone());
+    }
+
+    let passphrase = match std::env::var_os(PASSPHRASE_FILE_ENV) {
+        Some(path) => read_passphrase_file(path)?,
+        None => {
+            let passphrase = rpassword::prompt_password("otpcli passphrase: ").map_err(|e| {
+                TotpConfigError(format!(
+                    "Unable to prompt for the passphrase ({}), set {} to read it from a file",
+                    e, PASSPHRASE_FILE_ENV
+                ))
+            })?;
+            non_empty(SecretString::new(passphrase))?
+        }
+    };
+    *cached = Some(passphrase.clone());
+    Ok(passphrase)
+}
+
+/// Uses `passphrase` for the rest of this invocation, e.g. after changing it
+pub(crate) fn remember(passphrase: SecretString) {
+    *PASSPHRASE.lock().expect("passphrase cache poisoned") = Some(passphrase);
+}
+
+/// Reads a passphrase from the first line of a file
+pub fn read_passphrase_file<P: AsRef<Path>>(path: P) -> TotpResult<SecretString> {
+    let contents = SecretString::new(std::fs::read_to_string(path)?);
+    let line = contents.expose().lines().next().unwrap_or("");
+    non_empty(SecretString::from(line))
+}
+
+fn non_empty(passphrase: SecretString) -> TotpResult<SecretString> {
+    if passphrase.is_empty() {
+        Err(Box::new(TotpError("The passphrase is empty")))
+    } else {
+        Ok(passphrase)
+    }
+}
+
+/// Decrypts the `secret_enc` or `pin_enc` value of the entry `name` with the passphrase of this
+/// invocation
+pub(crate) fn open(name: &str, field: Field, sealed: &str) -> TotpResult<SecretString> {
+    let sealed = Sealed::parse(sealed)?;
+    sealed.open(&cached_key(&sealed.params)?, name, field)
+}
+
+/// Encrypts `secret` as the `field` of the entry `name`, with the same passphrase and salt as
+/// the already encrypted `sealed`
+pub(crate) fn seal_like(
+    sealed: &str,
+    name: &str,
+    field: Field,
+    secret: &SecretString,
+) -> TotpResult<String> {
+    Sealer::for_sealed(sealed)?.seal(name, field, secret)
+}
+
+fn is_config_stored(options: &TotpOptions) -> bool {
+    options.storage().unwrap_or(&SecretLocation::Config) == &SecretLocation::Config
+}
+
+/// A sealer for the passphrase `config` is encrypted with, or `None` if it isn't encrypted.
+///
+/// The passphrase is checked by decrypting one of the existing secrets, so new secrets can't
+/// end up encrypted with a mistyped one.
+pub(crate) fn config_sealer(config: &Config) -> TotpResult<Option<Sealer>> {
+    let mut names: Vec<&String> = config.codes().keys().collect();
+    names.sort();
+    let sealed = names
+        .into_iter()
+        .filter_map(|name| Some((name, config.codes()[name].encrypted_secret()?)))
+        .next();
+    match sealed {
+        Some((name, sealed)) => {
+            open(name, Field::Secret, sealed)?;
+            Ok(Some(Sealer::for_sealed(sealed)?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Encrypts the config-stored secret and PIN of the entry `name` in place
+pub(crate) fn seal_entry(name: &str, options: &mut TotpOptions, sealer: &Sealer) -> TotpResult<()> {
+    if !is_config_stored(options) {
+        return Ok(());
+    }
+    if let Some(secret) = options.secret().cloned() {
+        options.set_encrypted_secret(Some(sealer.seal(name, Field::Secret, &secret)?));
+        options.set_secret(None);
+    }
+    if let Some(pin) = options.pin().cloned() {
+        options.set_encrypted_pin(Some(sealer.seal(name, Field::Pin, &pin)?));
+        options.set_pin(None);
+    }
+    Ok(())
+}
+
+/// Encrypts a new config-stored entry like the rest of `config`, if it is encrypted
+pub(crate) fn seal_entry_like(
+    config: &Config,
+    name: &str,
+    options: &mut TotpOptions,
+) -> TotpResult<()> {
+    if !is_config_stored(options) {
+        return Ok(());
+    }
+    match config_sealer(config)? {
+        Some(sealer) => seal_entry(name, options, &sealer),
+        None => Ok(()),
+    }
+}
+
+/// Re-encrypts the config-stored secret and PIN of the entry `from` for the entry `to`, for
+/// copies and renames
+pub(crate) fn reseal_entry(from: &str, to: &str, options: &TotpOptions) -> TotpResult<TotpOptions> {
+    let mut options = options.clone();
+    if !is_config_stored(&options) {
+        return Ok(options);
+    }
+    if let Some(sealed) = options.encrypted_secret().cloned() {
+        let secret = open(from, Field::Secret, &sealed)?;
+        options.set_encrypted_secret(Some(seal_like(&sealed, to, Field::Secret, &secret)?));
+    }
+    if let Some(sealed) = options.encrypted_pin().cloned() {
+        let pin = open(from, Field::Pin, &sealed)?;
+        options.set_encrypted_pin(Some(seal_like(&sealed, to, Field::Pin, &pin)?));
+    }
+    Ok(options)
+}
+
+/// Decrypts the config-stored secret and PIN of an entry in place
+pub(crate) fn unseal_entry(name: &str, options: &mut TotpOptions) -> TotpResult<()> {
+    if !is_config_stored(options) {
+        return Ok(());
+    }
+    let secret = secrets::get_secret(name, options)?;
+    let pin = secrets::get_pin(name, options)?;
+    options.set_secret(Some(secret));
+    options.set_pin(pin);
+    options.set_encrypted_secret(None);
+    options.set_encrypted_pin(None);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cheap cost, so the tests don't spend their time deriving keys
+    const TEST_LOG_N: u8 = 4;
+
+    fn sealer(passphrase: &str) -> TotpResult<Sealer> {
+        Sealer::with_cost(&SecretString::from(passphrase), TEST_LOG_N)
+    }
+
+    /// The passphrase remembered for the tests that go through the passphrase of the process,
+    /// which is shared by every test
+    const PASSPHRASE: &str = "correct horse";
+    const SECRET: &str = "GEZDGNBVGY3TQOJQ";
+    const PIN: &str = "5937";
+
+    /// A config with one entry, its secret and PIN encrypted with [`PASSPHRASE`]
+    fn encrypted_config() -> TotpResult<Config> {
+        remember(SecretString::from(PASSPHRASE));
+        let mut options = TotpOptions::new_config_stored_secret(
+            SecretString::from(SECRET),
+            crate::totp::TokenAlgorithm::Motp,
+        );
+        options.set_pin(Some(SecretString::from(PIN)));
+        seal_entry("example", &mut options, &sealer(PASSPHRASE)?)?;
+        let mut config = Config::default();
+        config.insert("example".to_string(), options);
+        Ok(config)
+    }
+
+    #[test]
+    fn sealed_secrets_open_with_the_same_passphrase() -> TotpResult<()> {
+        let sealer = sealer("correct horse")?;
+        let sealed = sealer.seal(
+            "example",
+            Field::Secret,
+            &SecretString::from("GEZDGNBVGY3TQOJQ"),
+        )?;
+
+        assert!(sealed.starts_with("scrypt$4$"));
+        assert!(!sealed.contains("GEZDGNBVGY3TQOJQ"));
+
+        let parsed = Sealed::parse(&sealed)?;
+        let key = derive_key(&SecretString::from("correct horse"), &parsed.params)?;
+        assert_eq!(
+            parsed.open(&key, "example", Field::Secret)?.expose(),
+            "GEZDGNBVGY3TQOJQ"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sealed_values_only_open_for_their_entry_and_field() -> TotpResult<()> {
+        let sealed =
+            sealer("correct horse")?.seal("example", Field::Pin, &SecretString::from(PIN))?;
+
+        let parsed = Sealed::parse(&sealed)?;
+        let key = derive_key(&SecretString::from("correct horse"), &parsed.params)?;
+        assert!(parsed.open(&key, "example", Field::Pin).is_ok());
+        assert!(parsed.open(&key, "example", Field::Secret).is_err());
+        assert!(parsed.open(&key, "other", Field::Pin).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sealing_uses_a_new_nonce_each_time() -> TotpResult<()> {
+        let sealer = sealer("correct horse")?;
+        let secret = SecretString::from("GEZDGNBVGY3TQOJQ");
+        assert_ne!(
+            sealer.seal("example", Field::Secret, &secret)?,
+            sealer.seal("example", Field::Secret, &secret)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn the_wrong_passphrase_is_rejected() -> TotpResult<()> {
+        let secret = SecretString::from("GEZDGNBVGY3TQOJQ");
+        let sealed = sealer("correct horse")?.seal("example", Field::Secret, &secret)?;
+
+        let parsed = Sealed::parse(&sealed)?;
+        let key = derive_key(&SecretString::from("battery staple"), &parsed.params)?;
+        assert!(parsed.open(&key, "example", Field::Secret).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_values_are_rejected() {
+        assert!(Sealed::parse("").is_err());
+        assert!(Sealed::parse("GEZDGNBVGY3TQOJQ").is_err());
+        assert!(Sealed::parse("argon2$4$AAAA$AAAA$AAAA").is_err());
+        assert!(Sealed::parse("scrypt$x$AAAA$AAAA$AAAA").is_err());
+        assert!(Sealed::parse("scrypt$4$AAAA$AAAA$AAAA").is_err());
+    }
+
+    #[test]
+    fn encrypted_secrets_and_pins_are_read_with_the_passphrase() -> TotpResult<()> {
+        let config = encrypted_config()?;
+        let options = config.lookup("example")?;
+        assert!(options.secret().is_none() && options.pin().is_none());
+        assert!(options.encrypted_pin().is_some());
+
+        assert_eq!(secrets::get_secret("example", options)?.expose(), SECRET);
+        assert_eq!(
+            secrets::get_pin("example", options)?.map(|pin| pin.expose().to_string()),
+            Some(PIN.to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn new_entries_are_encrypted_like_the_config() -> TotpResult<()> {
+        let new_entry = || {
+            TotpOptions::new_config_stored_secret(
+                SecretString::from(SECRET),
+                crate::totp::TokenAlgorithm::TotpSha1,
+            )
+        };
+
+        let mut options = new_entry();
+        seal_entry_like(&encrypted_config()?, "new", &mut options)?;
+        assert!(options.is_encrypted() && options.secret().is_none());
+        assert_eq!(secrets::get_secret("new", &options)?.expose(), SECRET);
+
+        let mut options = new_entry();
+        seal_entry_like(&Config::default(), "new", &mut options)?;
+        assert!(!options.is_encrypted());
+        assert_eq!(options.secret().map(SecretString::expose), Some(SECRET));
+        Ok(())
+    }
+
+    #[test]
+    fn renamed_entries_are_encrypted_for_their_new_name() -> TotpResult<()> {
+        let dir = std::env::temp_dir().join(format!("otpcli-reseal-test-{}", std::process::id()));
+        let result = crate::rename_secret(encrypted_config()?, &dir, "example", "renamed");
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = result?;
+
+        let options = config.lookup("renamed")?;
+        assert!(options.is_encrypted());
+        assert_eq!(secrets::get_secret("renamed", options)?.expose(), SECRET);
+        assert_eq!(
+            secrets::get_pin("renamed", options)?.map(|pin| pin.expose().to_string()),
+            Some(PIN.to_string())
+        );
+        assert!(secrets::get_secret("example", options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn changing_the_passphrase_reencrypts_or_decrypts_the_config() -> TotpResult<()> {
+        let dir = std::env::temp_dir().join(format!("otpcli-passwd-test-{}", std::process::id()));
+        let config = encrypted_config()?;
+        let before = config.lookup("example")?.encrypted_secret().cloned();
+
+        let config = crate::reseal_config(config, &dir, Some(&sealer(PASSPHRASE)?))?;
+        let options = config.lookup("example")?;
+        assert!(options.is_encrypted());
+        assert_ne!(options.encrypted_secret(), before.as_ref());
+        assert_eq!(secrets::get_secret("example", options)?.expose(), SECRET);
+
+        let config = crate::change_passphrase(config, &dir, None)?;
+        let options = crate::config::load_config(&dir)?.lookup("example")?.clone();
+        assert!(!options.is_encrypted() && options.encrypted_pin().is_none());
+        assert_eq!(options.secret().map(SecretString::expose), Some(SECRET));
+        assert_eq!(options.pin().map(SecretString::expose), Some(PIN));
+        assert!(config.lookup("example")?.secret().is_some());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
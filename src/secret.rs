@@ -0,0 +1,154 @@
+//! Wrappers that keep secrets out of logs and wipe them from memory when dropped.
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use zeroize::Zeroize;
+
+use crate::mac;
+
+/// What secrets are shown as in `Debug` output
+pub(crate) const REDACTED: &str = "<redacted>";
+
+/// A secret string (a base32 secret, PIN or exported token) that is zeroized on drop
+#[derive(Clone, Default)]
+pub struct SecretString(String);
+
+/// Secret key bytes that are zeroized on drop
+#[derive(Clone, Default)]
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretString {
+    pub fn new(secret: String) -> Self {
+        SecretString(secret)
+    }
+
+    /// The secret itself; avoid copying it into values that outlive this wrapper
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl SecretBytes {
+    pub fn new(secret: Vec<u8>) -> Self {
+        SecretBytes(secret)
+    }
+
+    /// The secret itself; avoid copying it into values that outlive this wrapper
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        SecretString(secret)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(secret: &str) -> Self {
+        SecretString(secret.to_string())
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(secret: Vec<u8>) -> Self {
+        SecretBytes(secret)
+    }
+}
+
+impl From<&[u8]> for SecretBytes {
+    fn from(secret: &[u8]) -> Self {
+        SecretBytes(secret.to_vec())
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Debug for SecretString {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "SecretString({})", REDACTED)
+    }
+}
+
+impl Debug for SecretBytes {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "SecretBytes({})", REDACTED)
+    }
+}
+
+impl PartialEq for SecretString {
+    fn eq(&self, other: &Self) -> bool {
+        mac::constant_time_eq(self.0.as_bytes(), other.0.as_bytes())
+    }
+}
+
+impl Eq for SecretString {}
+
+impl PartialEq for SecretBytes {
+    fn eq(&self, other: &Self) -> bool {
+        mac::constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SecretBytes {}
+
+impl Serialize for SecretString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(SecretString)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_output_is_redacted() {
+        let secret = SecretString::from("GEZDGNBVGY3TQOJQ");
+        let key = SecretBytes::from(&b"12345678901234567890"[..]);
+
+        assert_eq!(format!("{:?}", secret), "SecretString(<redacted>)");
+        assert_eq!(format!("{:?}", key), "SecretBytes(<redacted>)");
+        assert_eq!(
+            format!("{:?}", Some(secret)),
+            "Some(SecretString(<redacted>))"
+        );
+    }
+
+    #[test]
+    fn secrets_compare_by_value() {
+        assert_eq!(SecretString::from("1234"), SecretString::from("1234"));
+        assert_ne!(SecretString::from("1234"), SecretString::from("1235"));
+        assert_eq!(SecretBytes::from(vec![1, 2]), SecretBytes::from(vec![1, 2]));
+        assert_ne!(SecretBytes::from(vec![1, 2]), SecretBytes::from(vec![1]));
+    }
+}
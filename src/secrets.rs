@@ -1,41 +1,359 @@
 use crate::config::{SecretLocation, TotpOptions};
+use crate::passphrase::{self, Field};
+use crate::secret::SecretString;
 use crate::{totp::TokenAlgorithm, TotpError, TotpResult};
 #[cfg(feature = "keychain")]
-use keyring::Keyring;
+use keyring::{Keyring, KeyringError};
 
-pub fn get_secret(_name: &str, totp_config: &TotpOptions) -> TotpResult<String> {
-    let secret = totp_config.secret();
+/// The keychain service name every otpcli secret is stored under
+#[cfg(feature = "keychain")]
+pub const KEYCHAIN_SERVICE: &str = "urn:otpcli";
+
+/// The keychain service PINs are stored under, keyed by the same name as their secret
+#[cfg(feature = "keychain")]
+const KEYCHAIN_PIN_SERVICE: &str = "urn:otpcli:pin";
+
+pub fn get_secret(name: &str, totp_config: &TotpOptions) -> TotpResult<SecretString> {
     match totp_config.storage().unwrap_or(&SecretLocation::Config) {
-        SecretLocation::Config => Ok(secret
-            .cloned()
-            .ok_or(TotpError("Config secret but no secret"))?),
+        SecretLocation::Config => match (totp_config.secret(), totp_config.encrypted_secret()) {
+            (Some(secret), _) => Ok(secret.clone()),
+            (None, Some(sealed)) => passphrase::open(name, Field::Secret, sealed),
+            (None, None) => Err(Box::new(TotpError("Config secret but no secret"))),
+        },
+        #[cfg(feature = "keychain")]
+        SecretLocation::KeyChain => {
+            let keyring = Keyring::new(KEYCHAIN_SERVICE, name);
+            Ok(SecretString::new(keyring.get_password()?))
+        }
+    }
+}
+
+/// Looks up the PIN stored alongside the secret of `name`, if it has one
+pub fn get_pin(name: &str, totp_config: &TotpOptions) -> TotpResult<Option<SecretString>> {
+    match totp_config.storage().unwrap_or(&SecretLocation::Config) {
+        SecretLocation::Config => match (totp_config.pin(), totp_config.encrypted_pin()) {
+            (Some(pin), _) => Ok(Some(pin.clone())),
+            (None, Some(sealed)) => Ok(Some(passphrase::open(name, Field::Pin, sealed)?)),
+            (None, None) => Ok(None),
+        },
+        #[cfg(feature = "keychain")]
+        SecretLocation::KeyChain if totp_config.has_keychain_pin() => {
+            let keyring = Keyring::new(KEYCHAIN_PIN_SERVICE, name);
+            Ok(Some(SecretString::new(keyring.get_password()?)))
+        }
+        #[cfg(feature = "keychain")]
+        SecretLocation::KeyChain => Ok(None),
+    }
+}
+
+/// Stores a PIN in the same backend as the secret of `name`, recording it in `totp_config`
+pub fn store_pin(name: &str, totp_config: &mut TotpOptions, pin: &SecretString) -> TotpResult<()> {
+    match totp_config.storage().unwrap_or(&SecretLocation::Config) {
+        SecretLocation::Config => match totp_config.encrypted_secret() {
+            Some(sealed) => {
+                let pin = passphrase::seal_like(sealed, name, Field::Pin, pin)?;
+                totp_config.set_encrypted_pin(Some(pin));
+            }
+            None => totp_config.set_pin(Some(pin.clone())),
+        },
         #[cfg(feature = "keychain")]
         SecretLocation::KeyChain => {
-            let keyring = Keyring::new("urn:otpcli", _name);
-            Ok(keyring.get_password()?)
+            Keyring::new(KEYCHAIN_PIN_SERVICE, name).set_password(pin.expose())?;
+            totp_config.set_keychain_pin(true);
         }
     }
+    Ok(())
 }
 
+/// Where new secrets are stored: the keychain when it is available, the config otherwise
 #[cfg(feature = "keychain")]
-fn store(name: &str, secret: &str, algorithm: TokenAlgorithm) -> TotpResult<TotpOptions> {
-    let keyring = Keyring::new("urn:otpcli", name);
-    keyring.set_password(secret)?;
-    Ok(TotpOptions::new_keychain_stored_secret(algorithm))
+pub(crate) fn default_location() -> SecretLocation {
+    SecretLocation::KeyChain
 }
 
 #[cfg(not(feature = "keychain"))]
-fn store(_name: &str, secret: &str, algorithm: TokenAlgorithm) -> TotpResult<TotpOptions> {
-    Ok(TotpOptions::new_config_stored_secret(
-        secret.to_string(),
-        algorithm,
-    ))
+pub(crate) fn default_location() -> SecretLocation {
+    SecretLocation::Config
 }
 
-pub fn store_secret(
-    name: &str,
-    secret: &str,
+/// Stores a secret in a specific backend rather than the default one.
+pub fn store_secret_at(
+    location: &SecretLocation,
+    _name: &str,
+    secret: &SecretString,
     algorithm: TokenAlgorithm,
 ) -> TotpResult<TotpOptions> {
-    store(name, secret, algorithm)
+    match location {
+        SecretLocation::Config => Ok(TotpOptions::new_config_stored_secret(
+            secret.clone(),
+            algorithm,
+        )),
+        #[cfg(feature = "keychain")]
+        SecretLocation::KeyChain => {
+            let keyring = Keyring::new(KEYCHAIN_SERVICE, _name);
+            keyring.set_password(secret.expose())?;
+            Ok(TotpOptions::new_keychain_stored_secret(algorithm))
+        }
+    }
+}
+
+/// Copies the secret stored for one entry into the same backend under a new name,
+/// returning the options that reference the copy.
+pub fn copy_secret(_from: &str, _to: &str, totp_config: &TotpOptions) -> TotpResult<TotpOptions> {
+    match totp_config.storage().unwrap_or(&SecretLocation::Config) {
+        SecretLocation::Config => passphrase::reseal_entry(_from, _to, totp_config),
+        #[cfg(feature = "keychain")]
+        SecretLocation::KeyChain => {
+            let secret = get_secret(_from, totp_config)?;
+            Keyring::new(KEYCHAIN_SERVICE, _to).set_password(secret.expose())?;
+            if let Some(pin) = get_pin(_from, totp_config)? {
+                if let Err(e) = Keyring::new(KEYCHAIN_PIN_SERVICE, _to).set_password(pin.expose()) {
+                    let _ = Keyring::new(KEYCHAIN_SERVICE, _to).delete_password();
+                    return Err(Box::new(e));
+                }
+            }
+            Ok(totp_config.clone())
+        }
+    }
+}
+
+/// Removes the keychain PIN of `name` when its `replaced` entry had one and the `new` one doesn't.
+pub fn remove_replaced_pin(
+    _name: &str,
+    _replaced: &TotpOptions,
+    _new: &TotpOptions,
+) -> TotpResult<()> {
+    #[cfg(feature = "keychain")]
+    {
+        if _replaced.has_keychain_pin() && !_new.has_keychain_pin() {
+            Keyring::new(KEYCHAIN_PIN_SERVICE, _name).delete_password()?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the secret stored for `name` from its backend, if it is stored outside the config.
+pub fn remove_secret(_name: &str, totp_config: &TotpOptions) -> TotpResult<()> {
+    match totp_config.storage().unwrap_or(&SecretLocation::Config) {
+        SecretLocation::Config => Ok(()),
+        #[cfg(feature = "keychain")]
+        SecretLocation::KeyChain => {
+            if totp_config.has_keychain_pin() {
+                Keyring::new(KEYCHAIN_PIN_SERVICE, _name).delete_password()?;
+            }
+            Keyring::new(KEYCHAIN_SERVICE, _name).delete_password()?;
+            Ok(())
+        }
+    }
+}
+
+/// Lists the names of every secret otpcli has stored in the OS keychain.
+#[cfg(all(feature = "keychain", target_os = "linux"))]
+pub fn keychain_names() -> TotpResult<Vec<String>> {
+    use secret_service::{EncryptionType, SecretService};
+
+    let ss = SecretService::new(EncryptionType::Dh)?;
+    let mut names = Vec::new();
+    for service in [KEYCHAIN_SERVICE, KEYCHAIN_PIN_SERVICE].iter() {
+        for item in ss.search_items(vec![("service", *service)])? {
+            if let Some((_, name)) = item
+                .get_attributes()?
+                .into_iter()
+                .find(|(key, _)| key == "username")
+            {
+                names.push(name);
+            }
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(all(feature = "keychain", not(target_os = "linux")))]
+pub fn keychain_names() -> TotpResult<Vec<String>> {
+    Err(Box::new(TotpError(
+        "Listing keychain secrets is not supported on this platform",
+    )))
+}
+
+/// Removes a keychain secret by name, regardless of whether any config entry references it.
+#[cfg(feature = "keychain")]
+pub fn remove_keychain_secret(name: &str) -> TotpResult<()> {
+    // Not every secret has a PIN, and a PIN can outlive its secret, so only finding neither fails
+    let pin = Keyring::new(KEYCHAIN_PIN_SERVICE, name).delete_password();
+    match Keyring::new(KEYCHAIN_SERVICE, name).delete_password() {
+        Err(KeyringError::NoPasswordFound) if pin.is_ok() => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// Reads and writes secrets for adds and migrations: the backends above, or ones that misbehave on
+/// purpose in tests
+pub(crate) trait Backend {
+    fn get_secret(&self, name: &str, options: &TotpOptions) -> TotpResult<SecretString>;
+    fn get_pin(&self, name: &str, options: &TotpOptions) -> TotpResult<Option<SecretString>>;
+    fn store_secret_at(
+        &self,
+        location: &SecretLocation,
+        name: &str,
+        secret: &SecretString,
+        algorithm: TokenAlgorithm,
+    ) -> TotpResult<TotpOptions>;
+    fn store_pin(
+        &self,
+        name: &str,
+        options: &mut TotpOptions,
+        pin: &SecretString,
+    ) -> TotpResult<()>;
+    fn remove_secret(&self, name: &str, options: &TotpOptions) -> TotpResult<()>;
+    fn remove_replaced_pin(
+        &self,
+        name: &str,
+        replaced: &TotpOptions,
+        new: &TotpOptions,
+    ) -> TotpResult<()>;
+}
+
+pub(crate) struct Secrets;
+
+impl Backend for Secrets {
+    fn get_secret(&self, name: &str, options: &TotpOptions) -> TotpResult<SecretString> {
+        get_secret(name, options)
+    }
+
+    fn get_pin(&self, name: &str, options: &TotpOptions) -> TotpResult<Option<SecretString>> {
+        get_pin(name, options)
+    }
+
+    fn store_secret_at(
+        &self,
+        location: &SecretLocation,
+        name: &str,
+        secret: &SecretString,
+        algorithm: TokenAlgorithm,
+    ) -> TotpResult<TotpOptions> {
+        store_secret_at(location, name, secret, algorithm)
+    }
+
+    fn store_pin(
+        &self,
+        name: &str,
+        options: &mut TotpOptions,
+        pin: &SecretString,
+    ) -> TotpResult<()> {
+        store_pin(name, options, pin)
+    }
+
+    fn remove_secret(&self, name: &str, options: &TotpOptions) -> TotpResult<()> {
+        remove_secret(name, options)
+    }
+
+    fn remove_replaced_pin(
+        &self,
+        name: &str,
+        replaced: &TotpOptions,
+        new: &TotpOptions,
+    ) -> TotpResult<()> {
+        remove_replaced_pin(name, replaced, new)
+    }
+}
+
+/// An in-memory keychain that can store the wrong secret or refuse to store PINs
+#[cfg(all(test, feature = "keychain"))]
+#[derive(Default)]
+pub(crate) struct FakeKeychain {
+    pub(crate) secrets: std::cell::RefCell<std::collections::HashMap<String, SecretString>>,
+    pub(crate) pins: std::cell::RefCell<std::collections::HashMap<String, SecretString>>,
+    pub(crate) corrupt_secrets: bool,
+    pub(crate) fail_pins: bool,
+}
+
+#[cfg(all(test, feature = "keychain"))]
+impl FakeKeychain {
+    fn is_keychain(options: &TotpOptions) -> bool {
+        options.storage() == Some(&SecretLocation::KeyChain)
+    }
+
+    fn missing() -> Box<dyn std::error::Error> {
+        Box::new(TotpError("no such keychain item"))
+    }
+}
+
+#[cfg(all(test, feature = "keychain"))]
+impl Backend for FakeKeychain {
+    fn get_secret(&self, name: &str, options: &TotpOptions) -> TotpResult<SecretString> {
+        if !Self::is_keychain(options) {
+            return get_secret(name, options);
+        }
+        self.secrets
+            .borrow()
+            .get(name)
+            .cloned()
+            .ok_or_else(Self::missing)
+    }
+
+    fn get_pin(&self, name: &str, options: &TotpOptions) -> TotpResult<Option<SecretString>> {
+        if !Self::is_keychain(options) {
+            return get_pin(name, options);
+        }
+        Ok(self.pins.borrow().get(name).cloned())
+    }
+
+    fn store_secret_at(
+        &self,
+        location: &SecretLocation,
+        name: &str,
+        secret: &SecretString,
+        algorithm: TokenAlgorithm,
+    ) -> TotpResult<TotpOptions> {
+        if location != &SecretLocation::KeyChain {
+            return store_secret_at(location, name, secret, algorithm);
+        }
+        let secret = if self.corrupt_secrets {
+            SecretString::from("JBSWY3DPEHPK3PXP")
+        } else {
+            secret.clone()
+        };
+        self.secrets.borrow_mut().insert(name.to_string(), secret);
+        Ok(TotpOptions::new_keychain_stored_secret(algorithm))
+    }
+
+    fn store_pin(
+        &self,
+        name: &str,
+        options: &mut TotpOptions,
+        pin: &SecretString,
+    ) -> TotpResult<()> {
+        if !Self::is_keychain(options) {
+            return store_pin(name, options, pin);
+        }
+        if self.fail_pins {
+            return Err(Box::new(TotpError("the keychain refused the PIN")));
+        }
+        self.pins.borrow_mut().insert(name.to_string(), pin.clone());
+        options.set_keychain_pin(true);
+        Ok(())
+    }
+
+    fn remove_secret(&self, name: &str, options: &TotpOptions) -> TotpResult<()> {
+        if !Self::is_keychain(options) {
+            return remove_secret(name, options);
+        }
+        self.pins.borrow_mut().remove(name);
+        self.secrets
+            .borrow_mut()
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(Self::missing)
+    }
+    fn remove_replaced_pin(
+        &self,
+        name: &str,
+        replaced: &TotpOptions,
+        new: &TotpOptions,
+    ) -> TotpResult<()> {
+        if replaced.has_keychain_pin() && !new.has_keychain_pin() {
+            self.pins.borrow_mut().remove(name);
+        }
+        Ok(())
+    }
 }
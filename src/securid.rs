@@ -0,0 +1,663 @@
+//! Imports RSA SecurID software tokens from `.sdtid` files and CTF token strings.
+//!
+//! `.sdtid` files are the XML token batches handed out by RSA Authentication Manager, while CTF
+//! ("compressed token format") strings are the long numeric strings, optionally wrapped in a
+//! `com.rsa.securid://ctf?ctfData=...` URI, used to provision the mobile apps. Both are decoded
+//! into a [`stoken::RSAToken`] that is exported and stored like any other secret.
+use crypto::aessafe::{AesSafe128Decryptor, AesSafe128Encryptor};
+use crypto::symmetriccipher::{BlockDecryptor, BlockEncryptor};
+use serde::Deserialize;
+use stoken::{RSAToken, TokenDuration};
+
+use crate::{TotpConfigError, TotpError, TotpResult};
+
+const AES_SIZE: usize = 16;
+
+const VERSION_CHARS: usize = 1;
+const SERIAL_CHARS: usize = 12;
+const BINENC_OFFSET: usize = VERSION_CHARS + SERIAL_CHARS;
+const BINENC_BITS: usize = 189;
+const CHECKSUM_OFFSET: usize = BINENC_OFFSET + BINENC_BITS / 3;
+const CHECKSUM_BITS: usize = 15;
+const CTF_V2_CHARS: usize = CHECKSUM_OFFSET + CHECKSUM_BITS / 3;
+
+const MAX_PASSWORD_CHARS: usize = 40;
+/// The longest batch name the secret of an `.sdtid` file can be decrypted with
+const MAX_SDTID_NAME_BYTES: usize = 8;
+const KEY_HASH_MAGIC: [u8; 6] = [0xd8, 0xf5, 0x32, 0x53, 0x82, 0x89];
+
+const FLAG_128BIT: u32 = 1 << 14;
+const FLAG_PASSWORD_PROTECTED: u32 = 1 << 13;
+const FLAG_DEVICE_PROTECTED: u32 = 1 << 12;
+const FLAG_DIGITS_SHIFT: u32 = 6;
+const FLAG_DIGITS_MASK: u32 = 0x07 << FLAG_DIGITS_SHIFT;
+const FLAG_INTERVAL_MASK: u32 = 0x03;
+
+/// URI prefixes the RSA mobile apps accept in front of a CTF string
+const CTF_URI_PREFIXES: [&str; 3] = [
+    "com.rsa.securid.iphone://ctf?ctfData=",
+    "com.rsa.securid://ctf?ctfData=",
+    "http://127.0.0.1/securid/ctf?ctfData=",
+];
+
+/// Settings used when importing a SecurID token
+#[derive(Debug, Default, Clone)]
+pub struct ImportOptions {
+    /// The token PIN, added digit-wise to every tokencode (4 to 8 digits)
+    pub pin: Option<String>,
+    /// The password protecting a CTF token, if it was issued with one
+    pub password: Option<String>,
+    /// The device ID a CTF token is bound to, if it was issued for a specific device
+    pub device_id: Option<String>,
+    /// Overrides the number of digits (6 or 8) the token was issued with
+    pub digits: Option<usize>,
+}
+
+impl ImportOptions {
+    fn pin(&self) -> TotpResult<String> {
+        let pin = self.pin.clone().unwrap_or_default();
+        if !pin.is_empty() && (pin.len() < 4 || pin.len() > 8) {
+            return Err(Box::new(TotpError(
+                "A token PIN must be 4 to 8 digits long",
+            )));
+        }
+        if !pin.chars().all(|c| c.is_ascii_digit()) {
+            return Err(Box::new(TotpError("A token PIN may only contain digits")));
+        }
+        Ok(pin)
+    }
+
+    fn apply(&self, mut token: RSAToken) -> TotpResult<RSAToken> {
+        token.pin = self.pin()?;
+        if let Some(digits) = self.digits {
+            if digits != 6 && digits != 8 {
+                return Err(Box::new(TotpError("Tokencodes must be 6 or 8 digits long")));
+            }
+            token.digits = digits;
+        }
+        Ok(token)
+    }
+}
+
+/// The fields of an `.sdtid` file that are checked before decoding it, as [`RSAToken::from_xml`]
+/// panics on values it can't handle
+#[derive(Deserialize)]
+struct SdtidBatch {
+    #[serde(rename = "TKNHeader")]
+    header: SdtidHeader,
+    #[serde(rename = "TKN")]
+    token: SdtidToken,
+}
+
+#[derive(Deserialize)]
+struct SdtidHeader {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Secret")]
+    secret: String,
+    #[serde(rename = "DefInterval")]
+    interval: i32,
+    #[serde(rename = "DefDigits")]
+    digits: usize,
+}
+
+#[derive(Deserialize)]
+struct SdtidToken {
+    #[serde(rename = "Seed")]
+    seed: String,
+}
+
+/// Checks that an `.sdtid` field is the base64 encoding of a single AES block
+fn check_sdtid_block(field: &str, name: &str) -> TotpResult<()> {
+    match base64::decode(field) {
+        Ok(block) if block.len() == AES_SIZE => Ok(()),
+        _ => Err(Box::new(TotpConfigError(format!(
+            "The {} of the .sdtid file is malformed",
+            name
+        )))),
+    }
+}
+
+/// Decodes the contents of an `.sdtid` file.
+pub fn read_sdtid(contents: &str, options: &ImportOptions) -> TotpResult<RSAToken> {
+    if options.password.is_some() || options.device_id.is_some() {
+        return Err(Box::new(TotpError(
+            "Passwords and device IDs are only supported for CTF tokens",
+        )));
+    }
+
+    let batch: SdtidBatch = serde_xml_rs::from_str(contents)?;
+    if batch.header.interval != 30 && batch.header.interval != 60 {
+        return Err(Box::new(TotpConfigError(format!(
+            "Unsupported token interval of {} seconds",
+            batch.header.interval
+        ))));
+    }
+    if batch.header.digits == 0 || batch.header.digits > 8 {
+        return Err(Box::new(TotpConfigError(format!(
+            "Unsupported tokencode length of {} digits",
+            batch.header.digits
+        ))));
+    }
+    if batch.header.name.len() > MAX_SDTID_NAME_BYTES {
+        return Err(Box::new(TotpConfigError(format!(
+            "The batch name of an .sdtid file may be at most {} bytes long",
+            MAX_SDTID_NAME_BYTES
+        ))));
+    }
+    check_sdtid_block(&batch.header.secret, "secret")?;
+    // The seed is prefixed with a single character, usually `=`, that isn't part of its encoding
+    let seed = batch.token.seed;
+    check_sdtid_block(seed.get(1..).unwrap_or(""), "token seed")?;
+
+    let token = RSAToken::from_xml(serde_xml_rs::from_str(contents)?, "");
+    options.apply(token)
+}
+
+/// Decodes a version 2 CTF token string, with or without one of the mobile app URI prefixes.
+pub fn read_ctf(ctf: &str, options: &ImportOptions) -> TotpResult<RSAToken> {
+    let ctf = ctf.trim();
+    let (data, is_smartphone) = match strip_ctf_uri(ctf) {
+        Some(data) => (data, true),
+        None => (ctf, false),
+    };
+
+    // CTF strings are often handed out in dash or space separated groups
+    let data: String = data
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect();
+    if !data.chars().all(|c| c.is_ascii_digit()) {
+        return Err(Box::new(TotpError("A CTF token may only contain digits")));
+    }
+    if !data.starts_with('2') {
+        return Err(Box::new(TotpError(
+            "Only version 2 CTF tokens are supported",
+        )));
+    }
+    if data.len() != CTF_V2_CHARS {
+        return Err(Box::new(TotpConfigError(format!(
+            "A version 2 CTF token must be {} digits long",
+            CTF_V2_CHARS
+        ))));
+    }
+
+    let data = data.as_bytes();
+    let checksum = get_bits(
+        &digits_to_bits(&data[CHECKSUM_OFFSET..], CHECKSUM_BITS),
+        0,
+        CHECKSUM_BITS,
+    );
+    if checksum != short_mac(&data[..CHECKSUM_OFFSET]) {
+        return Err(Box::new(TotpError(
+            "The CTF token checksum is invalid, check for typos",
+        )));
+    }
+
+    let fields = digits_to_bits(&data[BINENC_OFFSET..CHECKSUM_OFFSET], BINENC_BITS);
+    let flags = get_bits(&fields, 128, 16);
+    let seed_hash = get_bits(&fields, 159, 15);
+    let device_id_hash = get_bits(&fields, 174, 15);
+
+    if flags & FLAG_128BIT == 0 {
+        return Err(Box::new(TotpError(
+            "64-bit SecurID tokens are not supported",
+        )));
+    }
+
+    let password = if flags & FLAG_PASSWORD_PROTECTED != 0 {
+        Some(options.password.as_deref().ok_or(TotpError(
+            "This token is password protected, a password is required",
+        ))?)
+    } else {
+        None
+    };
+    let device_id = if flags & FLAG_DEVICE_PROTECTED != 0 {
+        Some(options.device_id.as_deref().ok_or(TotpError(
+            "This token is bound to a device, a device ID is required",
+        ))?)
+    } else {
+        None
+    };
+
+    let device_id_len = if is_smartphone { 40 } else { 32 };
+    let (key, computed_device_id_hash) = key_hash(password, device_id, device_id_len)?;
+    if device_id.is_some() && computed_device_id_hash != device_id_hash {
+        return Err(Box::new(TotpError(
+            "The device ID does not match the one this token was issued for",
+        )));
+    }
+
+    let mut seed = [0u8; AES_SIZE];
+    AesSafe128Decryptor::new(&key).decrypt_block(&fields[..AES_SIZE], &mut seed);
+    if short_mac(&seed) != seed_hash {
+        return Err(Box::new(TotpError(
+            "Unable to decrypt the token seed, check the password",
+        )));
+    }
+
+    let duration = match flags & FLAG_INTERVAL_MASK {
+        0 => TokenDuration::ThirtySecond,
+        _ => TokenDuration::SixtySecond,
+    };
+    let digits = (((flags & FLAG_DIGITS_MASK) >> FLAG_DIGITS_SHIFT) + 1) as usize;
+    let serial = String::from_utf8_lossy(&data[VERSION_CHARS..BINENC_OFFSET]).to_string();
+
+    let token = RSAToken::new(serial, duration, digits, seed.to_vec(), String::new());
+    options.apply(token)
+}
+
+/// Decodes either the contents of an `.sdtid` file or a CTF token string.
+pub fn read_token(input: &str, options: &ImportOptions) -> TotpResult<RSAToken> {
+    if input.trim_start().starts_with('<') {
+        read_sdtid(input, options)
+    } else {
+        read_ctf(input, options)
+    }
+}
+
+fn strip_ctf_uri(ctf: &str) -> Option<&str> {
+    CTF_URI_PREFIXES
+        .iter()
+        .find_map(|prefix| ctf.strip_prefix(prefix))
+}
+
+/// Unpacks 3 bits from each ASCII digit into a big-endian bit string
+fn digits_to_bits(digits: &[u8], n_bits: usize) -> Vec<u8> {
+    let mut out = vec![0u8; n_bits.div_ceil(8)];
+    let mut bit = 0;
+    for digit in digits {
+        let value = (digit.wrapping_sub(b'0')) & 0x07;
+        for shift in (0..3).rev() {
+            if bit < n_bits && (value >> shift) & 1 == 1 {
+                out[bit / 8] |= 0x80 >> (bit % 8);
+            }
+            bit += 1;
+        }
+    }
+    out
+}
+
+fn get_bits(bits: &[u8], start: usize, n_bits: usize) -> u32 {
+    (start..start + n_bits).fold(0, |value, bit| {
+        (value << 1) | u32::from((bits[bit / 8] >> (7 - bit % 8)) & 1)
+    })
+}
+
+fn encrypt_then_xor(key: &[u8], work: &mut [u8; AES_SIZE]) {
+    let mut encrypted = [0u8; AES_SIZE];
+    AesSafe128Encryptor::new(key).encrypt_block(work, &mut encrypted);
+    for (w, e) in work.iter_mut().zip(encrypted.iter()) {
+        *w ^= e;
+    }
+}
+
+/// The AES based hash SecurID uses for checksums and key derivation
+fn securid_mac(input: &[u8]) -> [u8; AES_SIZE] {
+    let mut work = [0xffu8; AES_SIZE];
+
+    let mut pad = [0u8; AES_SIZE];
+    let mut bit_len = input.len() * 8;
+    for byte in pad.iter_mut().rev() {
+        if bit_len == 0 {
+            break;
+        }
+        *byte = (bit_len & 0xff) as u8;
+        bit_len >>= 8;
+    }
+
+    let mut rest = input;
+    let mut odd = false;
+    while rest.len() > AES_SIZE {
+        encrypt_then_xor(&rest[..AES_SIZE], &mut work);
+        rest = &rest[AES_SIZE..];
+        odd = !odd;
+    }
+
+    let mut last = [0u8; AES_SIZE];
+    last[..rest.len()].copy_from_slice(rest);
+    encrypt_then_xor(&last, &mut work);
+
+    if odd {
+        encrypt_then_xor(&[0u8; AES_SIZE], &mut work);
+    }
+    encrypt_then_xor(&pad, &mut work);
+
+    let mut out = work;
+    encrypt_then_xor(&work, &mut out);
+    out
+}
+
+fn short_mac(input: &[u8]) -> u32 {
+    let hash = securid_mac(input);
+    (u32::from(hash[0]) << 7) | (u32::from(hash[1]) >> 1)
+}
+
+/// Derives the seed decryption key from the (optional) password and device ID,
+/// also returning the hash of the device ID that is embedded in the token.
+fn key_hash(
+    password: Option<&str>,
+    device_id: Option<&str>,
+    device_id_len: usize,
+) -> TotpResult<([u8; AES_SIZE], u32)> {
+    let password = password.unwrap_or_default();
+    if password.len() > MAX_PASSWORD_CHARS {
+        return Err(Box::new(TotpError(
+            "The token's password can't be longer than 40 characters",
+        )));
+    }
+    let mut key: Vec<u8> = password.bytes().collect();
+
+    let mut device_id_buf = vec![0u8; device_id_len];
+    let device_id_chars = device_id
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .take(device_id_len)
+        .map(|c| c.to_ascii_uppercase() as u8);
+    let mut len = 0;
+    for (slot, c) in device_id_buf.iter_mut().zip(device_id_chars) {
+        *slot = c;
+        len += 1;
+    }
+    key.extend_from_slice(&device_id_buf[..len]);
+    key.extend_from_slice(&KEY_HASH_MAGIC);
+
+    Ok((securid_mac(&key), short_mac(&device_id_buf)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stoken::chrono::{TimeZone, Utc};
+
+    const SAMPLE_SDTID: &str = include_str!("../tests/fixtures/test.sdtid");
+
+    fn sample_time() -> stoken::chrono::DateTime<Utc> {
+        // 2019-01-13 21:19:34 UTC
+        Utc.timestamp(1_547_414_374, 0)
+    }
+
+    fn with_pin(pin: &str) -> ImportOptions {
+        ImportOptions {
+            pin: Some(pin.to_string()),
+            ..ImportOptions::default()
+        }
+    }
+
+    /// Packs token fields into a CTF string, the inverse of [`read_ctf`]
+    fn encode_ctf(
+        serial: &str,
+        seed: &[u8; AES_SIZE],
+        flags: u32,
+        password: Option<&str>,
+        device_id: Option<&str>,
+    ) -> String {
+        let (key, device_id_hash) = key_hash(password, device_id, 32).unwrap();
+        let mut encrypted_seed = [0u8; AES_SIZE];
+        AesSafe128Encryptor::new(&key).encrypt_block(seed, &mut encrypted_seed);
+
+        let mut bits: Vec<bool> = Vec::new();
+        let mut push = |value: u32, n_bits: usize| {
+            for shift in (0..n_bits).rev() {
+                bits.push((value >> shift) & 1 == 1);
+            }
+        };
+        for byte in encrypted_seed.iter() {
+            push(u32::from(*byte), 8);
+        }
+        push(flags, 16);
+        push(0, 15);
+        push(short_mac(seed), 15);
+        push(device_id_hash, 15);
+
+        let mut ctf = format!("2{}", serial);
+        for chunk in bits.chunks(3) {
+            let digit = chunk.iter().fold(0, |acc, bit| (acc << 1) | *bit as u8);
+            ctf.push((b'0' + digit) as char);
+        }
+        let checksum = short_mac(ctf.as_bytes());
+        for shift in (0..5).rev() {
+            ctf.push((b'0' + ((checksum >> (shift * 3)) & 0x07) as u8) as char);
+        }
+        ctf
+    }
+
+    const SEED: [u8; AES_SIZE] = *b"0123456789abcdef";
+    /// The token of the sample sdtid file as a CTF string, 60 second 8 digit codes
+    const SAMPLE_CTF: &str =
+        "232475452370975353531127061712510261624575254601622536664070100000644112716725313";
+    /// The same token protected with the password "hunter2"
+    const SAMPLE_CTF_PASSWORD: &str =
+        "232475452370915115140771347475130444471322006566166370446070100000644112716770367";
+    const FLAGS_8_DIGITS_60S: u32 = FLAG_128BIT | (7 << FLAG_DIGITS_SHIFT) | 1;
+
+    #[test]
+    fn sdtid_generates_reference_code() -> TotpResult<()> {
+        let token = read_sdtid(SAMPLE_SDTID, &with_pin("12345"))?;
+        assert_eq!(token.serial_number(), "324754523709");
+        assert_eq!(token.digits, 8);
+        assert_eq!(stoken::generate(token, sample_time()), "93659800");
+        Ok(())
+    }
+
+    #[test]
+    fn sdtid_digits_can_be_overridden() -> TotpResult<()> {
+        let options = ImportOptions {
+            digits: Some(6),
+            ..with_pin("12345")
+        };
+        let token = read_token(SAMPLE_SDTID, &options)?;
+        assert_eq!(stoken::generate(token, sample_time()), "659800");
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_pins_and_digits_are_rejected() {
+        assert!(read_sdtid(SAMPLE_SDTID, &with_pin("123")).is_err());
+        assert!(read_sdtid(SAMPLE_SDTID, &with_pin("123456789")).is_err());
+        assert!(read_sdtid(SAMPLE_SDTID, &with_pin("12a45")).is_err());
+
+        let options = ImportOptions {
+            digits: Some(7),
+            ..ImportOptions::default()
+        };
+        assert!(read_sdtid(SAMPLE_SDTID, &options).is_err());
+    }
+
+    #[test]
+    fn malformed_sdtid_is_an_error() {
+        assert!(read_sdtid("<TKNBatch></TKNBatch>", &ImportOptions::default()).is_err());
+        assert!(read_sdtid("not xml", &ImportOptions::default()).is_err());
+    }
+
+    #[test]
+    fn malformed_sdtid_seeds_and_secrets_are_errors() {
+        let seed = "<Seed>=YeJhH+VLPN4U9UFTC2RIog==</Seed>";
+        let secret = "<Secret>d9rOsdr9iL8BV1+swVC9tA==</Secret>";
+        for (from, to) in [
+            (seed, "<Seed></Seed>"),
+            (seed, "<Seed>=</Seed>"),
+            (seed, "<Seed>=not base64!</Seed>"),
+            (
+                seed,
+                "<Seed>=YeJhH+VLPN4U9UFTC2RIogYeJhH+VLPN4U9UFTC2RIog==</Seed>",
+            ),
+            (seed, "<Seed>éYeJhH+VLPN4U9UFTC2RIog==</Seed>"),
+            (secret, "<Secret>not base64!</Secret>"),
+            (secret, "<Secret>d9rO</Secret>"),
+            ("<Name>N/A</Name>", "<Name>a long name</Name>"),
+            (
+                "<DefInterval>60</DefInterval>",
+                "<DefInterval>45</DefInterval>",
+            ),
+        ]
+        .iter()
+        {
+            let sdtid = SAMPLE_SDTID.replace(from, to);
+            assert!(
+                read_sdtid(&sdtid, &ImportOptions::default()).is_err(),
+                "{} was accepted",
+                to
+            );
+        }
+    }
+
+    #[test]
+    fn sdtid_files_reject_ctf_only_options() {
+        let options = ImportOptions {
+            password: Some("password".to_string()),
+            ..ImportOptions::default()
+        };
+        assert!(read_sdtid(SAMPLE_SDTID, &options).is_err());
+    }
+
+    #[test]
+    fn bits_round_trip_through_digits() {
+        let bits = digits_to_bits(b"7070", 12);
+        assert_eq!(bits, vec![0b1110_0011, 0b1000_0000]);
+        assert_eq!(get_bits(&bits, 0, 3), 7);
+        assert_eq!(get_bits(&bits, 3, 3), 0);
+        assert_eq!(get_bits(&bits, 6, 6), 0b111_000);
+    }
+
+    #[test]
+    fn ctf_strings_decode_to_the_seed_of_the_sample_token() -> TotpResult<()> {
+        let sdtid = read_sdtid(SAMPLE_SDTID, &with_pin("12345"))?;
+        assert_eq!(
+            sdtid.dec_seed,
+            [
+                0x0a, 0xcf, 0x7d, 0xcc, 0x39, 0x78, 0x84, 0x8c, 0xb5, 0x70, 0xbb, 0x07, 0x21, 0x71,
+                0xe5, 0x9d
+            ]
+        );
+
+        let token = read_ctf(SAMPLE_CTF, &with_pin("12345"))?;
+        assert_eq!(token.serial_number(), "324754523709");
+        assert_eq!(token.dec_seed, sdtid.dec_seed);
+        assert_eq!(stoken::generate(token, sample_time()), "93659800");
+
+        let options = ImportOptions {
+            password: Some("hunter2".to_string()),
+            ..with_pin("12345")
+        };
+        let token = read_ctf(SAMPLE_CTF_PASSWORD, &options)?;
+        assert_eq!(stoken::generate(token, sample_time()), "93659800");
+        Ok(())
+    }
+
+    #[test]
+    fn passwords_longer_than_stoken_accepts_are_rejected() {
+        let options = |length| ImportOptions {
+            password: Some("p".repeat(length)),
+            ..ImportOptions::default()
+        };
+        assert!(read_ctf(SAMPLE_CTF_PASSWORD, &options(MAX_PASSWORD_CHARS + 1)).is_err());
+        assert!(key_hash(Some(&"p".repeat(MAX_PASSWORD_CHARS)), None, 32).is_ok());
+    }
+
+    #[test]
+    fn ctf_tokens_are_decoded() -> TotpResult<()> {
+        let ctf = encode_ctf("123456789012", &SEED, FLAGS_8_DIGITS_60S, None, None);
+        assert_eq!(ctf.len(), CTF_V2_CHARS);
+
+        let token = read_ctf(&ctf, &with_pin("1234"))?;
+        assert_eq!(token.serial_number(), "123456789012");
+        assert_eq!(token.digits, 8);
+        assert_eq!(token.token_duration, TokenDuration::SixtySecond);
+        assert_eq!(token.dec_seed, SEED.to_vec());
+        assert_eq!(token.pin, "1234");
+        Ok(())
+    }
+
+    #[test]
+    fn ctf_tokens_accept_separators_and_report_typos() -> TotpResult<()> {
+        let ctf = encode_ctf(
+            "123456789012",
+            &SEED,
+            FLAG_128BIT | (5 << FLAG_DIGITS_SHIFT),
+            None,
+            None,
+        );
+        let grouped: Vec<String> = ctf
+            .as_bytes()
+            .chunks(9)
+            .map(|c| String::from_utf8_lossy(c).to_string())
+            .collect();
+        let token = read_ctf(&grouped.join("-"), &ImportOptions::default())?;
+        assert_eq!(token.digits, 6);
+        assert_eq!(token.token_duration, TokenDuration::ThirtySecond);
+
+        let mut typo = ctf.into_bytes();
+        typo[20] = if typo[20] == b'1' { b'2' } else { b'1' };
+        let typo = String::from_utf8(typo).unwrap();
+        assert!(read_ctf(&typo, &ImportOptions::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn password_protected_ctf_tokens_need_the_password() -> TotpResult<()> {
+        let flags = FLAGS_8_DIGITS_60S | FLAG_PASSWORD_PROTECTED;
+        let ctf = encode_ctf("123456789012", &SEED, flags, Some("hunter2"), None);
+
+        assert!(read_ctf(&ctf, &ImportOptions::default()).is_err());
+
+        let wrong = ImportOptions {
+            password: Some("hunter3".to_string()),
+            ..ImportOptions::default()
+        };
+        assert!(read_ctf(&ctf, &wrong).is_err());
+
+        let right = ImportOptions {
+            password: Some("hunter2".to_string()),
+            ..ImportOptions::default()
+        };
+        assert_eq!(read_ctf(&ctf, &right)?.dec_seed, SEED.to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn device_bound_ctf_tokens_check_the_device_id() -> TotpResult<()> {
+        let flags = FLAGS_8_DIGITS_60S | FLAG_DEVICE_PROTECTED;
+        let ctf = encode_ctf("123456789012", &SEED, flags, None, Some("0123456789abcdef"));
+
+        assert!(read_ctf(&ctf, &ImportOptions::default()).is_err());
+
+        let wrong = ImportOptions {
+            device_id: Some("fedcba9876543210".to_string()),
+            ..ImportOptions::default()
+        };
+        assert!(read_ctf(&ctf, &wrong).is_err());
+
+        let right = ImportOptions {
+            device_id: Some("0123-4567-89AB-CDEF".to_string()),
+            ..ImportOptions::default()
+        };
+        assert_eq!(read_ctf(&ctf, &right)?.dec_seed, SEED.to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn ctf_uris_are_unwrapped() {
+        assert_eq!(
+            strip_ctf_uri("com.rsa.securid://ctf?ctfData=2000"),
+            Some("2000")
+        );
+        assert_eq!(
+            strip_ctf_uri("http://127.0.0.1/securid/ctf?ctfData=2000"),
+            Some("2000")
+        );
+        assert_eq!(strip_ctf_uri("2000"), None);
+    }
+
+    #[test]
+    fn unsupported_ctf_tokens_are_rejected() {
+        let options = ImportOptions::default();
+        assert!(read_ctf("1234", &options).is_err());
+        assert!(read_ctf(&"1".repeat(CTF_V2_CHARS), &options).is_err());
+        assert!(read_ctf("2abc", &options).is_err());
+
+        let ctf = encode_ctf("123456789012", &SEED, 7 << FLAG_DIGITS_SHIFT, None, None);
+        assert!(read_ctf(&ctf, &options).is_err());
+    }
+}